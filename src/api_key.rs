@@ -0,0 +1,95 @@
+use crate::ApiContext;
+use crate::error::{BadRequestError, DatabaseError, RequestError};
+use crate::models::{ApiKey, ApiKeyRequest};
+use crate::util;
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+
+/// All api keys start with this, so we can tell them apart from session tokens
+pub const KEY_PREFIX: &str = "gb_";
+
+/// Api keys can't live longer then a year
+const MAX_EXPIRY: u32 = 31536000;
+
+/// How many keys a single user can have
+const MAX_KEYS: usize = 25;
+
+/// Creates a new api key, returns it's info and the key itself
+pub async fn create_api_key(ctx: &Arc<ApiContext>, user_id: u64, request: ApiKeyRequest) -> Result<(ApiKey, String), RequestError> {
+    let name = request.name.trim();
+    if name.is_empty() || name.len() > 64 || request.scopes.is_empty() || request.expires_in == 0 || request.expires_in > MAX_EXPIRY {
+        return Err(BadRequestError::InvalidBody.into());
+    }
+    //no route is about a single guild yet, so keys limited to one wouldn't work anywhere
+    if request.scopes.iter().any(|scope| scope.guild.is_some()) {
+        return Err(BadRequestError::InvalidBody.into());
+    }
+    if get_api_keys(ctx, user_id).await?.len() >= MAX_KEYS {
+        return Err(BadRequestError::TooManyApiKeys.into());
+    }
+
+    let key = format!("{}{}", KEY_PREFIX, util::random_token(32));
+    let hash = hash_key(&key);
+    let now = util::now();
+    let info = ApiKey {
+        //the start of the hash is unique enough to tell them apart, and useless to figure out the key
+        id: hash[..16].to_string(),
+        hash,
+        user_id,
+        name: name.to_string(),
+        scopes: request.scopes,
+        created_at: now,
+        expires_at: now + u64::from(request.expires_in),
+    };
+
+    ctx.redis_link.set(&format!("api_key:{}", info.hash), &info, Some(request.expires_in)).await?;
+    let index = format!("user_api_keys:{}", user_id);
+    ctx.redis_link.hash_set(&index, &info.id, &info).await?;
+
+    Ok((info, key))
+}
+
+/// Lists all keys of this user that didn't expire yet
+pub async fn get_api_keys(ctx: &Arc<ApiContext>, user_id: u64) -> Result<Vec<ApiKey>, DatabaseError> {
+    let index = format!("user_api_keys:{}", user_id);
+    let now = util::now();
+    let mut keys = vec![];
+    for info in ctx.redis_link.hash_values::<ApiKey>(&index).await? {
+        if info.expires_at <= now {
+            ctx.redis_link.hash_delete(&index, &info.id).await?;
+        } else {
+            keys.push(info);
+        }
+    }
+    keys.sort_by_key(|info| info.created_at);
+    Ok(keys)
+}
+
+/// Revokes one of the keys of this user
+///
+/// Returns `false` if the user has no key with this id
+pub async fn revoke_api_key(ctx: &Arc<ApiContext>, user_id: u64, id: &str) -> Result<bool, DatabaseError> {
+    let index = format!("user_api_keys:{}", user_id);
+    match ctx.redis_link.hash_get::<ApiKey>(&index, id).await? {
+        Some(info) => {
+            ctx.redis_link.delete(&format!("api_key:{}", info.hash)).await?;
+            ctx.redis_link.hash_delete(&index, id).await?;
+            Ok(true)
+        }
+        None => Ok(false)
+    }
+}
+
+/// Looks up the info of an api key, expired keys are treated as if they don't exist
+pub async fn get_api_key(ctx: &Arc<ApiContext>, key: &str) -> Result<Option<ApiKey>, DatabaseError> {
+    Ok(ctx.redis_link.get::<ApiKey>(&format!("api_key:{}", hash_key(key))).await?
+        .filter(|info| info.expires_at > util::now()))
+}
+
+fn hash_key(key: &str) -> String {
+    hex(&Sha256::digest(key.as_bytes()))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
@@ -1,16 +1,111 @@
+use crate::cookie::CookieConfig;
 use crate::error::StartupError;
+use crate::metrics::MetricsConfig;
+use crate::middleware::{CorsConfig, RateLimitConfig};
+use crate::redis::cluster::ClusterConfig;
+use crate::redis::streams::StreamsConfig;
+use crate::tls::TlsConfig;
 use serde::Deserialize;
+use std::fmt;
 use std::fs;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
 
 #[derive(Deserialize, Debug)]
 pub struct ApiConfig {
     pub redis: String,
     pub port: u16,
+    /// addresses to listen on, like `0.0.0.0:4000`, `[::]:4000` or `unix:/run/gearbot/api.sock`, addresses without a port use `port`
+    ///
+    /// only `127.0.0.1` on `port` if left out
+    #[serde(default)]
+    pub bind: Vec<String>,
+    /// permissions for unix sockets we listen on
+    #[serde(default = "default_socket_mode")]
+    pub socket_mode: u32,
+    /// how long (in seconds) we wait for requests and websockets to finish when shutting down
+    #[serde(default = "default_shutdown_timeout")]
+    pub shutdown_timeout: u64,
     pub application_id: u64,
     pub client_secret: String,
     pub redirect_uri: String,
     pub domain: String,
-    pub secure: bool
+    pub secure: bool,
+    /// base64 encoded 32 byte key used to encrypt discord refresh tokens
+    ///
+    /// required, but left out it's empty so startup can explain how to generate one
+    #[serde(default)]
+    pub token_encryption_key: String,
+    /// set when running behind a reverse proxy, so we get the ip of the client instead of the proxy
+    ///
+    /// only turn this on if every request passes through the proxy, anyone can send this header otherwise
+    #[serde(default)]
+    pub trust_forwarded_for: bool,
+    /// how many proxies in front of us append to `X-Forwarded-For`, the client is this many entries from the right
+    #[serde(default = "default_proxy_hops")]
+    pub proxy_hops: usize,
+    /// where discord sends people after inviting GearBot, `/api/discord/invite/callback` on our domain if left out
+    #[serde(default)]
+    pub invite_redirect_uri: Option<String>,
+    /// where the dashboard lives, `/dashboard` on our domain if left out
+    #[serde(default)]
+    pub dashboard_url: Option<String>,
+    /// dashboard page of a guild, `{guild_id}` is replaced with the id of the guild, `{dashboard_url}/{guild_id}` if left out
+    #[serde(default)]
+    pub guild_dashboard_url: Option<String>,
+    /// permissions GearBot asks for when being invited
+    #[serde(default)]
+    pub invite_permissions: Option<u64>,
+    /// hosts (other then our own domain) we are allowed to send people to after they logged in
+    #[serde(default)]
+    pub redirect_allowlist: Vec<String>,
+    #[serde(default)]
+    pub cookie: CookieConfig,
+    #[serde(default)]
+    pub cors: CorsConfig,
+    /// requests per client for each rate limit bucket routes can be in
+    #[serde(default)]
+    pub rate_limits: RateLimitConfig,
+    /// serve https ourselves instead of leaving that to a reverse proxy
+    #[serde(default)]
+    pub tls: Option<TlsConfig>,
+    /// talk to GearBot over redis streams instead of pub/sub, so requests survive bot restarts
+    #[serde(default)]
+    pub streams: Option<StreamsConfig>,
+    /// how GearBot is split into clusters, requests go to the cluster that can answer them
+    #[serde(default)]
+    pub clusters: Option<ClusterConfig>,
+    /// prometheus metrics, not exposed unless enabled
+    #[serde(default)]
+    pub metrics: MetricsConfig,
+}
+
+/// Somewhere to listen for requests
+#[derive(Debug, Clone)]
+pub enum BindAddress {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl fmt::Display for BindAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BindAddress::Tcp(addr) => write!(f, "{}", addr),
+            BindAddress::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+fn default_socket_mode() -> u32 {
+    0o660
+}
+
+fn default_proxy_hops() -> usize {
+    1
+}
+
+fn default_shutdown_timeout() -> u64 {
+    30
 }
 
 impl ApiConfig {
@@ -18,4 +113,53 @@ impl ApiConfig {
         let config_file = fs::read_to_string(filename).map_err(|_| StartupError::NoConfig)?;
         toml::from_str::<ApiConfig>(&config_file).map_err(|_| StartupError::InvalidConfig)
     }
+
+    pub fn bind_addresses(&self) -> Result<Vec<BindAddress>, StartupError> {
+        if self.bind.is_empty() {
+            return Ok(vec![BindAddress::Tcp(SocketAddr::from(([127, 0, 0, 1], self.port)))]);
+        }
+        self.bind.iter()
+            .map(|address| {
+                if let Some(path) = address.strip_prefix("unix:") {
+                    Ok(BindAddress::Unix(PathBuf::from(path)))
+                } else if let Ok(addr) = address.parse::<SocketAddr>() {
+                    Ok(BindAddress::Tcp(addr))
+                } else if let Ok(ip) = address.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>() {
+                    Ok(BindAddress::Tcp(SocketAddr::new(ip, self.port)))
+                } else {
+                    Err(StartupError::InvalidConfig)
+                }
+            })
+            .collect()
+    }
+
+    pub fn protocol(&self) -> &'static str {
+        if self.secure { "https" } else { "http" }
+    }
+
+    /// The origin pages on our own domain have, like `https://gearbot.rocks`
+    pub fn origin(&self) -> String {
+        format!("{}://{}", self.protocol(), self.domain)
+    }
+
+    pub fn invite_redirect_uri(&self) -> String {
+        match &self.invite_redirect_uri {
+            Some(uri) => uri.clone(),
+            None => format!("{}://{}/api/discord/invite/callback", self.protocol(), self.domain)
+        }
+    }
+
+    pub fn dashboard_url(&self) -> String {
+        match &self.dashboard_url {
+            Some(url) => url.clone(),
+            None => format!("{}://{}/dashboard", self.protocol(), self.domain)
+        }
+    }
+
+    pub fn guild_dashboard_url(&self, guild_id: u64) -> String {
+        match &self.guild_dashboard_url {
+            Some(url) => url.replace("{guild_id}", &guild_id.to_string()),
+            None => format!("{}/{}", self.dashboard_url(), guild_id)
+        }
+    }
 }
@@ -0,0 +1,323 @@
+use crate::ApiContext;
+use crate::session::SESSION_EXPIRY;
+use hmac::{Hmac, Mac, NewMac};
+use hyper::{Body, Request};
+use hyper::header::COOKIE;
+use serde::Deserialize;
+use sha2::Sha256;
+use std::fmt;
+use std::sync::Arc;
+
+/// Name of the cookie that ties a login attempt to the browser that started it
+const LOGIN_COOKIE: &str = "login_session";
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum SameSite {
+    Strict,
+    Lax,
+    None,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+pub struct CookieConfig {
+    /// name of the session cookie
+    pub name: String,
+    /// domain the session cookie is valid for, only the domain that set it if left out
+    pub domain: Option<String>,
+    pub same_site: SameSite,
+    /// when set the session cookie is signed with this key, so forged cookies get rejected without looking them up
+    pub signing_key: Option<String>,
+}
+
+impl Default for CookieConfig {
+    fn default() -> Self {
+        CookieConfig {
+            name: "token".to_string(),
+            domain: None,
+            same_site: SameSite::Strict,
+            signing_key: None,
+        }
+    }
+}
+
+/// A Set-Cookie header value
+pub struct Cookie {
+    name: String,
+    value: String,
+    max_age: u32,
+    domain: Option<String>,
+    same_site: SameSite,
+    secure: bool,
+}
+
+impl Cookie {
+    pub fn new(name: &str, value: &str) -> Self {
+        Cookie {
+            name: name.to_string(),
+            value: value.to_string(),
+            max_age: 0,
+            domain: None,
+            same_site: SameSite::Strict,
+            secure: false,
+        }
+    }
+
+    /// A cookie that makes the browser forget about the cookie with this name
+    pub fn removal(name: &str) -> Self {
+        Cookie::new(name, "")
+    }
+
+    pub fn max_age(mut self, seconds: u32) -> Self {
+        self.max_age = seconds;
+        self
+    }
+
+    pub fn domain(mut self, domain: Option<String>) -> Self {
+        self.domain = domain;
+        self
+    }
+
+    pub fn same_site(mut self, same_site: SameSite) -> Self {
+        self.same_site = same_site;
+        self
+    }
+
+    pub fn secure(mut self, secure: bool) -> Self {
+        self.secure = secure;
+        self
+    }
+}
+
+impl fmt::Display for Cookie {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}; Max-Age={}; Path=/", self.name, self.value, self.max_age)?;
+        if let Some(domain) = &self.domain {
+            write!(f, "; Domain={}", domain)?;
+        }
+        //none is only accepted by browsers on secure cookies
+        if self.secure || self.same_site == SameSite::None {
+            write!(f, "; Secure")?;
+        }
+        //none of our cookies are any of javascript's business
+        write!(f, "; HttpOnly")?;
+        let same_site = match self.same_site {
+            SameSite::Strict => "Strict",
+            SameSite::Lax => "Lax",
+            SameSite::None => "None",
+        };
+        write!(f, "; SameSite={}", same_site)
+    }
+}
+
+/// The cookie holding the dashboard session
+pub fn session_cookie(ctx: &Arc<ApiContext>, token: &str) -> Cookie {
+    let value = match &ctx.config.cookie.signing_key {
+        Some(key) => format!("{}.{}", token, sign(key, token)),
+        None => token.to_string(),
+    };
+    Cookie::new(&ctx.config.cookie.name, &value)
+        .max_age(SESSION_EXPIRY)
+        .domain(ctx.config.cookie.domain.clone())
+        .same_site(ctx.config.cookie.same_site)
+        .secure(ctx.config.secure)
+}
+
+/// Clears the session cookie
+pub fn session_removal_cookie(ctx: &Arc<ApiContext>) -> Cookie {
+    Cookie::removal(&ctx.config.cookie.name)
+        .domain(ctx.config.cookie.domain.clone())
+        .same_site(ctx.config.cookie.same_site)
+        .secure(ctx.config.secure)
+}
+
+/// The pre-login cookie, lax instead of strict as we need it to be sent along when discord redirects the user back to us
+pub fn login_cookie(ctx: &Arc<ApiContext>, value: &str, max_age: u32) -> Cookie {
+    Cookie::new(LOGIN_COOKIE, value)
+        .max_age(max_age)
+        .same_site(SameSite::Lax)
+        .secure(ctx.config.secure)
+}
+
+/// Clears the pre-login cookie
+pub fn login_removal_cookie(ctx: &Arc<ApiContext>) -> Cookie {
+    Cookie::removal(LOGIN_COOKIE)
+        .same_site(SameSite::Lax)
+        .secure(ctx.config.secure)
+}
+
+/// Gets the value of the pre-login cookie
+pub fn get_login_session(request: &Request<Body>) -> Option<String> {
+    get_cookie(request, LOGIN_COOKIE)
+}
+
+/// Gets the session token from the session cookie
+///
+/// Other sites on a parent domain can set a cookie with the same name, so every one with our name is tried and the first that verifies wins
+pub fn get_session_cookie(ctx: &Arc<ApiContext>, request: &Request<Body>) -> Option<String> {
+    first_valid(ctx.config.cookie.signing_key.as_deref(), get_cookies(request, &ctx.config.cookie.name))
+}
+
+/// Turns the value of a session cookie back into the session token
+///
+/// If cookies are signed the signature is checked, `None` is returned for values with a bad signature
+pub fn verify_session_value(ctx: &Arc<ApiContext>, value: &str) -> Option<String> {
+    verify(ctx.config.cookie.signing_key.as_deref(), value)
+}
+
+fn first_valid(key: Option<&str>, values: Vec<String>) -> Option<String> {
+    values.iter().find_map(|value| verify(key, value))
+}
+
+fn verify(key: Option<&str>, value: &str) -> Option<String> {
+    match key {
+        Some(key) => {
+            let index = value.rfind('.')?;
+            let (token, signature) = value.split_at(index);
+            let signature = base64::decode_config(&signature[1..], base64::URL_SAFE_NO_PAD).ok()?;
+            let mut mac = Hmac::<Sha256>::new_varkey(key.as_bytes()).unwrap();
+            mac.update(token.as_bytes());
+            mac.verify(&signature).ok()?;
+            Some(token.to_string())
+        }
+        None => Some(value.to_string()),
+    }
+}
+
+/// Gets the value of a cookie
+///
+/// If the browser sent multiple cookies with this name, the first one wins. Browsers put the one with the most specific path first
+pub fn get_cookie(request: &Request<Body>, name: &str) -> Option<String> {
+    get_cookies(request, name).into_iter().next()
+}
+
+/// Gets the values of every cookie with this name, in the order the browser sent them
+pub fn get_cookies(request: &Request<Body>, name: &str) -> Vec<String> {
+    parse_cookies(request)
+        .into_iter()
+        .filter(|(cookie_name, _)| cookie_name == name)
+        .map(|(_, value)| value)
+        .collect()
+}
+
+/// Parses all cookies from the cookie headers as described in RFC 6265, section 5.4
+///
+/// Pairs without a name or `=` are skipped, and values wrapped in double quotes have their quotes removed
+pub fn parse_cookies(request: &Request<Body>) -> Vec<(String, String)> {
+    request.headers().get_all(COOKIE)
+        .iter()
+        //http/2 clients send a header per cookie, others put them all in one
+        .filter_map(|header| header.to_str().ok())
+        .flat_map(|header| header.split(';'))
+        .filter_map(|pair| {
+            let index = pair.find('=')?;
+            let (name, value) = pair.split_at(index);
+            let name = name.trim();
+            if name.is_empty() {
+                return None;
+            }
+            let value = value[1..].trim();
+            let value = if value.len() >= 2 && value.starts_with('"') && value.ends_with('"') {
+                &value[1..value.len() - 1]
+            } else {
+                value
+            };
+            Some((name.to_string(), value.to_string()))
+        })
+        .collect()
+}
+
+fn sign(key: &str, token: &str) -> String {
+    //hmac takes keys of any length
+    let mut mac = Hmac::<Sha256>::new_varkey(key.as_bytes()).unwrap();
+    mac.update(token.as_bytes());
+    base64::encode_config(mac.finalize().into_bytes(), base64::URL_SAFE_NO_PAD)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cookie_defaults() {
+        let cookie = Cookie::new("token", "abc").to_string();
+        assert_eq!(cookie, "token=abc; Max-Age=0; Path=/; HttpOnly; SameSite=Strict");
+    }
+
+    #[test]
+    fn cookie_with_everything() {
+        let cookie = Cookie::new("token", "abc")
+            .max_age(3600)
+            .domain(Some("gearbot.rocks".to_string()))
+            .same_site(SameSite::Lax)
+            .secure(true)
+            .to_string();
+        assert_eq!(cookie, "token=abc; Max-Age=3600; Path=/; Domain=gearbot.rocks; Secure; HttpOnly; SameSite=Lax");
+    }
+
+    #[test]
+    fn same_site_none_is_always_secure() {
+        let cookie = Cookie::new("token", "abc").same_site(SameSite::None).to_string();
+        assert!(cookie.contains("; Secure"));
+        assert!(cookie.ends_with("; SameSite=None"));
+    }
+
+    #[test]
+    fn removal_expires_right_away() {
+        let cookie = Cookie::removal("token").to_string();
+        assert!(cookie.starts_with("token=; Max-Age=0;"));
+    }
+
+    fn request(headers: &[&str]) -> Request<Body> {
+        let mut request = Request::builder();
+        for header in headers {
+            request = request.header(COOKIE, *header);
+        }
+        request.body(Body::empty()).unwrap()
+    }
+
+    fn pairs(cookies: &[(&str, &str)]) -> Vec<(String, String)> {
+        cookies.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect()
+    }
+
+    #[test]
+    fn parses_cookie_headers() {
+        let cookies = parse_cookies(&request(&["token=abc; theme=dark", "lang=en"]));
+        assert_eq!(cookies, pairs(&[("token", "abc"), ("theme", "dark"), ("lang", "en")]));
+    }
+
+    #[test]
+    fn trims_whitespace() {
+        let cookies = parse_cookies(&request(&["  token = abc ;theme=dark;  "]));
+        assert_eq!(cookies, pairs(&[("token", "abc"), ("theme", "dark")]));
+    }
+
+    #[test]
+    fn unquotes_values() {
+        let cookies = parse_cookies(&request(&[r#"token="abc"; empty=""; half="abc; eq=a=b"#]));
+        assert_eq!(cookies, pairs(&[("token", "abc"), ("empty", ""), ("half", "\"abc"), ("eq", "a=b")]));
+    }
+
+    #[test]
+    fn skips_broken_pairs() {
+        let cookies = parse_cookies(&request(&["novalue; =nameless; token=abc"]));
+        assert_eq!(cookies, pairs(&[("token", "abc")]));
+    }
+
+    #[test]
+    fn keeps_duplicates_in_order() {
+        let request = request(&["token=first; other=x; token=second"]);
+        assert_eq!(get_cookie(&request, "token"), Some("first".to_string()));
+        assert_eq!(get_cookies(&request, "token"), vec!["first".to_string(), "second".to_string()]);
+        assert_eq!(get_cookie(&request, "missing"), None);
+    }
+
+    #[test]
+    fn first_signed_duplicate_wins() {
+        let signed = format!("abc.{}", sign("key", "abc"));
+        let values = vec!["forged.AAAA".to_string(), "unsigned".to_string(), signed];
+        assert_eq!(first_valid(Some("key"), values.clone()), Some("abc".to_string()));
+        //without signing there's nothing to check, so the first one is used
+        assert_eq!(first_valid(None, values), Some("forged.AAAA".to_string()));
+    }
+}
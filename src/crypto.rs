@@ -0,0 +1,72 @@
+use crate::error::StartupError;
+use aes_gcm::aead::{Aead, NewAead};
+use aes_gcm::aead::generic_array::GenericArray;
+use aes_gcm::Aes256Gcm;
+use rand::Rng;
+
+const NONCE_SIZE: usize = 12;
+
+/// Encrypts secrets (like discord refresh tokens) before they go into redis, so a redis dump alone isn't enough to impersonate users
+pub struct TokenCipher {
+    cipher: Aes256Gcm,
+}
+
+impl TokenCipher {
+    /// Creates a cipher from a base64 encoded 32 byte key
+    pub fn new(key: &str) -> Result<Self, StartupError> {
+        if key.is_empty() {
+            return Err(StartupError::TokenKey("is not set"));
+        }
+        let key = base64::decode(key).map_err(|_| StartupError::TokenKey("is not valid base64"))?;
+        if key.len() != 32 {
+            return Err(StartupError::TokenKey("has to be 32 bytes"));
+        }
+        Ok(Self {
+            cipher: Aes256Gcm::new(GenericArray::from_slice(&key)),
+        })
+    }
+
+    /// Encrypts the secret, the result is the base64 encoded nonce followed by the ciphertext
+    pub fn encrypt(&self, secret: &str) -> String {
+        let mut nonce = [0u8; NONCE_SIZE];
+        rand::thread_rng().fill(&mut nonce);
+        let encrypted = self.cipher
+            .encrypt(GenericArray::from_slice(&nonce), secret.as_bytes())
+            .expect("Encrypting a secret failed");
+
+        let mut data = nonce.to_vec();
+        data.extend(encrypted);
+        base64::encode(data)
+    }
+
+    /// Decrypts a secret produced by `encrypt`
+    ///
+    /// Returns `None` if it was tampered with or encrypted with a different key
+    pub fn decrypt(&self, data: &str) -> Option<String> {
+        let data = base64::decode(data).ok()?;
+        if data.len() < NONCE_SIZE {
+            return None;
+        }
+        let (nonce, encrypted) = data.split_at(NONCE_SIZE);
+        let decrypted = self.cipher.decrypt(GenericArray::from_slice(nonce), encrypted).ok()?;
+        String::from_utf8(decrypted).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bad_keys_say_what_is_wrong() {
+        assert!(matches!(TokenCipher::new(""), Err(StartupError::TokenKey("is not set"))));
+        assert!(matches!(TokenCipher::new("not base64!"), Err(StartupError::TokenKey("is not valid base64"))));
+        assert!(matches!(TokenCipher::new("AAAA"), Err(StartupError::TokenKey("has to be 32 bytes"))));
+    }
+
+    #[test]
+    fn round_trips() {
+        let cipher = TokenCipher::new(&base64::encode([7u8; 32])).unwrap();
+        assert_eq!(cipher.decrypt(&cipher.encrypt("secret")).as_deref(), Some("secret"));
+    }
+}
@@ -1,15 +1,34 @@
 use hyper::StatusCode;
+use serde::Serialize;
 use std::fmt;
 use tokio::sync::oneshot::error::RecvError;
 use std::fmt::Formatter;
-use std::borrow::Cow;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+
+/// What clients get to see of an error
+#[derive(Serialize, Debug, Clone)]
+pub struct ErrorBody {
+    pub code: &'static str,
+    pub message: String,
+    pub request_id: String,
+}
+
+impl ErrorBody {
+    pub fn from_request_error(e: &RequestError, request_id: &str) -> Self {
+        ErrorBody { code: e.code(), message: e.to_string(), request_id: request_id.to_string() }
+    }
+}
 
-#[derive(Debug)]
 pub enum StartupError {
     NoConfig,
     InvalidConfig,
     NoLoggingSpec,
     DarkRedis(darkredis::Error),
+    Bind(String, std::io::Error),
+    Signal(std::io::Error),
+    Tls(String),
+    /// what is wrong with `token_encryption_key`
+    TokenKey(&'static str),
 }
 
 #[derive(Debug)]
@@ -17,7 +36,10 @@ pub enum RequestError {
     Server(ServerError),
     BadRequest(BadRequestError),
     NotFound,
+    Unauthorized,
     Forbidden,
+    /// the client went over the rate limit of the route, and can try again in this many seconds
+    TooManyRequests { retry_after: u64 },
 }
 
 #[derive(Debug)]
@@ -27,6 +49,8 @@ pub enum CommunicationError {
     DarkRedis(darkredis::Error),
     WrongReplyType,
     DataFormat(serde_json::Error),
+    /// we aren't listening for replies right now, so there is no point in asking
+    BotLinkDown,
 }
 
 #[derive(Debug)]
@@ -43,6 +67,11 @@ pub enum BadRequestError {
     UpgradeOnly,
     MissingWsKey,
     NoAccessCode,
+    InvalidOAuthState,
+    InvalidBody,
+    TooManyApiKeys,
+    InvalidGuildId,
+    InvalidRedirect,
 }
 
 #[derive(Debug)]
@@ -64,7 +93,9 @@ pub enum WSMessageError {
     AlreadyAuthorized,
     ClosedGracefully,
     NoValidDiscordAuthToken,
-    DiscordRequest(RequestError)
+    DiscordRequest(RequestError),
+    SessionRevoked,
+    ServerRestarting,
 }
 
 
@@ -80,32 +111,45 @@ impl fmt::Display for WSMessageError {
             WSMessageError::AlreadyAuthorized => write!(f, "Someone double identified"),
             WSMessageError::ClosedGracefully => write!(f, "Connection closed by client"),
             WSMessageError::NoValidDiscordAuthToken => write!(f, "No valid discord oauth2 token found"),
-            WSMessageError::DiscordRequest(e) => write!(f, "Failed to fetch information from the discord api: {}", e)
+            WSMessageError::DiscordRequest(e) => write!(f, "Failed to fetch information from the discord api: {}", e),
+            WSMessageError::SessionRevoked => write!(f, "The session of this websocket was revoked"),
+            WSMessageError::ServerRestarting => write!(f, "The server is shutting down"),
         }
     }
 }
 
 const CORRUPT_MESSAGE: &str = "Corrupt message recieved";
+const INTERNAL_ERROR: &str = "Something went wrong on our end, please try again later";
+const DISCORD_REQUEST: &str = "Failed to fetch information from discord";
+const CLOSED_GRACEFULLY: &str = "Session finished";
 const NOT_AUTHORIZED: &str = "You failed to identify yourself first, access denied!";
 const BAD_AUTHORIZATION: &str = "You failed to identify yourself first, access denied!";
 const ALREADY_AUTHORIZED: &str = "You can not identify twice!";
 const TUNGSTENITE: &str = "Unable to process message";
 const NO_VALID_DISCORD_AUTH: &str = "No valid discord oauth token was found in storage for this user";
+const SESSION_REVOKED: &str = "Your session has ended, please log in again";
+const SERVER_RESTARTING: &str = "Server restarting, please reconnect in a few seconds";
+const BOT_UNAVAILABLE: &str = "GearBot is unavailable right now, please try again later";
+
+/// Close code for sockets of sessions that got revoked (logged out), there is no point in reconnecting with the same token
+pub const SESSION_REVOKED_CLOSE_CODE: u16 = 4001;
 
 impl WSMessageError {
     pub fn closes_socket(&self) -> bool {
-        match self {
+        matches!(self,
             WSMessageError::Tungstenite(_) |
             WSMessageError::CorruptMessage(_) |
             WSMessageError::NotAuthorized |
             WSMessageError::AlreadyAuthorized |
             WSMessageError::BadAuthorization |
-            WSMessageError::NoValidDiscordAuthToken => true,
-            _ => false
-        }
+            WSMessageError::NoValidDiscordAuthToken |
+            WSMessageError::SessionRevoked |
+            WSMessageError::ServerRestarting
+        )
     }
 
-    pub fn get_close_message(&self) -> &'static str {
+    /// Message for the client, also used as close reason for errors that close the socket
+    pub fn get_message(&self) -> &'static str {
         match self {
             WSMessageError::CorruptMessage(_) => CORRUPT_MESSAGE,
             WSMessageError::NotAuthorized => NOT_AUTHORIZED,
@@ -113,7 +157,39 @@ impl WSMessageError {
             WSMessageError::AlreadyAuthorized => ALREADY_AUTHORIZED,
             WSMessageError::Tungstenite(_) => TUNGSTENITE,
             WSMessageError::NoValidDiscordAuthToken => NO_VALID_DISCORD_AUTH,
-            _ => unreachable!()
+            WSMessageError::SessionRevoked => SESSION_REVOKED,
+            WSMessageError::ServerRestarting => SERVER_RESTARTING,
+            WSMessageError::Communication(CommunicationError::BotLinkDown) => BOT_UNAVAILABLE,
+            WSMessageError::Database(_) | WSMessageError::Communication(_) => INTERNAL_ERROR,
+            WSMessageError::DiscordRequest(_) => DISCORD_REQUEST,
+            WSMessageError::ClosedGracefully => CLOSED_GRACEFULLY,
+        }
+    }
+
+    /// Stable code for clients to check for, see the error code table in the readme
+    pub fn code(&self) -> &'static str {
+        match self {
+            WSMessageError::Tungstenite(_) => "socket_error",
+            WSMessageError::Communication(CommunicationError::BotLinkDown) => "bot_unavailable",
+            WSMessageError::Database(_) | WSMessageError::Communication(_) => "internal_error",
+            WSMessageError::CorruptMessage(_) => "corrupt_message",
+            WSMessageError::NotAuthorized => "not_identified",
+            WSMessageError::BadAuthorization => "bad_authorization",
+            WSMessageError::AlreadyAuthorized => "already_identified",
+            WSMessageError::ClosedGracefully => "closed",
+            WSMessageError::NoValidDiscordAuthToken => "no_discord_token",
+            WSMessageError::DiscordRequest(_) => "discord_error",
+            WSMessageError::SessionRevoked => "session_revoked",
+            WSMessageError::ServerRestarting => "server_restarting",
+        }
+    }
+
+    pub fn get_close_code(&self) -> CloseCode {
+        match self {
+            WSMessageError::SessionRevoked => CloseCode::from(SESSION_REVOKED_CLOSE_CODE),
+            WSMessageError::ClosedGracefully => CloseCode::Normal,
+            WSMessageError::ServerRestarting => CloseCode::Restart,
+            _ => CloseCode::Error
         }
     }
 }
@@ -142,12 +218,28 @@ impl From<sqlx::Error> for DatabaseError {
 }
 
 impl RequestError {
+    /// Stable code for clients to check for, see the error code table in the readme
+    pub fn code(&self) -> &'static str {
+        match self {
+            RequestError::Server(ServerError::Communication(CommunicationError::BotLinkDown)) => "bot_unavailable",
+            RequestError::Server(_) => "internal_error",
+            RequestError::BadRequest(e) => e.code(),
+            RequestError::NotFound => "not_found",
+            RequestError::Unauthorized => "unauthorized",
+            RequestError::Forbidden => "forbidden",
+            RequestError::TooManyRequests { .. } => "rate_limited",
+        }
+    }
+
     pub fn get_status(&self) -> StatusCode {
         match self {
+            RequestError::Server(ServerError::Communication(CommunicationError::BotLinkDown)) => StatusCode::SERVICE_UNAVAILABLE,
             RequestError::Server(_) => StatusCode::INTERNAL_SERVER_ERROR,
             RequestError::BadRequest(_) => StatusCode::BAD_REQUEST,
             RequestError::NotFound => StatusCode::NOT_FOUND,
+            RequestError::Unauthorized => StatusCode::UNAUTHORIZED,
             RequestError::Forbidden => StatusCode::FORBIDDEN,
+            RequestError::TooManyRequests { .. } => StatusCode::TOO_MANY_REQUESTS,
         }
     }
 }
@@ -155,14 +247,24 @@ impl RequestError {
 impl fmt::Display for RequestError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
+            RequestError::Server(ServerError::Communication(CommunicationError::BotLinkDown)) => write!(f, "{}", BOT_UNAVAILABLE),
             RequestError::Server(_) => write!(f, "Internal server error!"),
-            RequestError::BadRequest(e) => write!(f, "Bad request! {}", e),
+            RequestError::BadRequest(e) => write!(f, "{}", e),
             RequestError::NotFound => write!(f, "Unknown route"),
+            RequestError::Unauthorized => write!(f, "Not logged in"),
             RequestError::Forbidden => write!(f, "Access denied"),
+            RequestError::TooManyRequests { retry_after } => write!(f, "Too many requests, try again in {} seconds", retry_after),
         }
     }
 }
 
+//main returns these, which prints them with debug
+impl fmt::Debug for StartupError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
 impl fmt::Display for StartupError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
@@ -170,6 +272,10 @@ impl fmt::Display for StartupError {
             StartupError::InvalidConfig => write!(f, "Config file is not valid"),
             StartupError::NoLoggingSpec => write!(f, "Unable to load log spec file"),
             StartupError::DarkRedis(e) => write!(f, "Error creating the redis pool: {}", e),
+            StartupError::Bind(address, e) => write!(f, "Unable to listen on {}: {}", address, e),
+            StartupError::Signal(e) => write!(f, "Unable to listen for shutdown signals: {}", e),
+            StartupError::Tls(e) => write!(f, "Unable to load the TLS certificate: {}", e),
+            StartupError::TokenKey(e) => write!(f, "token_encryption_key {}, generate one with `openssl rand -base64 32`", e),
         }
     }
 }
@@ -188,7 +294,32 @@ impl fmt::Display for ServerError {
 
 impl fmt::Display for BadRequestError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "placeholder")
+        match self {
+            BadRequestError::UpgradeOnly => write!(f, "This endpoint only accepts websocket upgrades"),
+            BadRequestError::MissingWsKey => write!(f, "No websocket key was provided"),
+            BadRequestError::NoAccessCode => write!(f, "Discord did not provide an access code"),
+            BadRequestError::InvalidOAuthState => write!(f, "The login state is invalid or expired, please try again"),
+            BadRequestError::InvalidBody => write!(f, "The request body is invalid"),
+            BadRequestError::TooManyApiKeys => write!(f, "You can't have any more api keys"),
+            BadRequestError::InvalidGuildId => write!(f, "Invalid guild id"),
+            BadRequestError::InvalidRedirect => write!(f, "Not allowed to redirect there"),
+        }
+    }
+}
+
+impl BadRequestError {
+    /// Stable code for clients to check for, see the error code table in the readme
+    pub fn code(&self) -> &'static str {
+        match self {
+            BadRequestError::UpgradeOnly => "upgrade_only",
+            BadRequestError::MissingWsKey => "missing_ws_key",
+            BadRequestError::NoAccessCode => "no_access_code",
+            BadRequestError::InvalidOAuthState => "invalid_oauth_state",
+            BadRequestError::InvalidBody => "invalid_body",
+            BadRequestError::TooManyApiKeys => "too_many_api_keys",
+            BadRequestError::InvalidGuildId => "invalid_guild_id",
+            BadRequestError::InvalidRedirect => "invalid_redirect",
+        }
     }
 }
 
@@ -206,6 +337,7 @@ impl fmt::Display for CommunicationError {
                 write!(f, "Received wrong reply data type for the requested data")
             }
             CommunicationError::DataFormat(e) => write!(f, "JSON was in an unexpected form: {}", e),
+            CommunicationError::BotLinkDown => write!(f, "Not connected to GearBot"),
         }
     }
 }
@@ -0,0 +1,67 @@
+use flexi_logger::{style, DeferredNow};
+use log::Record;
+use std::future::Future;
+use std::io;
+use tokio::task::JoinHandle;
+use uuid::Uuid;
+
+tokio::task_local! {
+    /// Id of the request the current task is working on
+    static REQUEST_ID: String;
+}
+
+/// Runs the future with the request id attached to everything logged while doing so
+pub async fn with_request_id<F: Future>(request_id: String, future: F) -> F::Output {
+    REQUEST_ID.scope(request_id, future).await
+}
+
+/// The id of the request being handled by the current task, if any
+pub fn current_request_id() -> Option<String> {
+    REQUEST_ID.try_with(|request_id| request_id.clone()).ok()
+}
+
+/// `tokio::spawn`, but the new task keeps the request id of the current one
+pub fn spawn<F>(future: F) -> JoinHandle<F::Output>
+    where F: Future + Send + 'static, F::Output: Send + 'static {
+    match current_request_id() {
+        Some(request_id) => tokio::spawn(with_request_id(request_id, future)),
+        None => tokio::spawn(future),
+    }
+}
+
+pub fn new_request_id() -> String {
+    Uuid::new_v4().to_string()
+}
+
+/// Ids from the outside end up in our logs, so they are kept short and simple
+pub fn valid_request_id(request_id: &str) -> bool {
+    !request_id.is_empty() && request_id.len() <= 128 &&
+        request_id.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | ':'))
+}
+
+/// Same as flexi_logger's `colored_opt_format`, but with the request id (if any) in front of the message
+pub fn format(w: &mut dyn io::Write, now: &mut DeferredNow, record: &Record) -> io::Result<()> {
+    let level = record.level();
+    write!(
+        w,
+        "[{}] {} [{}:{}] ",
+        style(level, now.now().format("%Y-%m-%d %H:%M:%S%.6f %:z")),
+        style(level, level),
+        record.file().unwrap_or("<unnamed>"),
+        record.line().unwrap_or(0),
+    )?;
+    REQUEST_ID.try_with(|request_id| write!(w, "[{}] ", request_id)).unwrap_or(Ok(()))?;
+    write!(w, "{}", style(level, &record.args()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn spawned_tasks_keep_the_request_id() {
+        let spawned = with_request_id("abc".to_string(), async { spawn(async { current_request_id() }).await.unwrap() }).await;
+        assert_eq!(spawned, Some("abc".to_string()));
+        assert_eq!(spawn(async { current_request_id() }).await.unwrap(), None);
+    }
+}
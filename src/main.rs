@@ -1,26 +1,37 @@
-use crate::config::ApiConfig;
-use crate::error::{RequestError, StartupError};
+use crate::config::{ApiConfig, BindAddress};
+use crate::crypto::TokenCipher;
+use crate::error::StartupError;
 use crate::redis::redis_link::RedisLink;
-use crate::routes::{hello_world, not_found, team_info, ws, discord::{login, auth, user_info}};
-use flexi_logger::{colored_opt_format, Age, Cleanup, Criterion, Duplicate, Logger, Naming};
-use hyper::header::ACCESS_CONTROL_ALLOW_ORIGIN;
-use hyper::service::{make_service_fn, service_fn};
-use hyper::{Body, Method, Request, Response, Server, Client};
-use log::{error, info};
-use std::convert::Infallible;
+use crate::metrics::Metrics;
+use crate::middleware::{AccessLog, Authenticate, Cors, Pipeline, RateLimiter, RenderErrors, RequestMetrics};
+use crate::shutdown::{ConnectionTracker, ShutdownSignal};
+use flexi_logger::{Age, Cleanup, Criterion, Duplicate, Logger, Naming};
+use hyper::Client;
+use log::{info, warn};
 use std::env;
-use std::net::SocketAddr;
+use std::fs;
 use std::sync::Arc;
-use twilight_model::id::ApplicationId;
+use tokio::sync::broadcast;
+use tokio::time::{timeout_at, Duration, Instant};
 use hyper_tls::HttpsConnector;
-use hyper_tls::native_tls::TlsConnector;
 use hyper::client::HttpConnector;
 
 
+mod api_key;
 mod config;
+mod cookie;
+mod crypto;
 mod error;
+mod logging;
+mod metrics;
+mod middleware;
 mod redis;
+mod router;
 mod routes;
+mod server;
+mod session;
+mod shutdown;
+mod tls;
 mod models;
 mod util;
 
@@ -28,6 +39,24 @@ pub struct ApiContext {
     pub config: ApiConfig,
     pub redis_link: RedisLink,
     pub client: Client<HttpsConnector<HttpConnector>>,
+    pub token_cipher: TokenCipher,
+    /// tokens of sessions that just got revoked, so websockets using them can be closed
+    pub revoked_sessions: broadcast::Sender<String>,
+    pub shutdown: ShutdownSignal,
+    /// websockets that should be closed before we exit
+    pub connections: Arc<ConnectionTracker>,
+    pub metrics: Arc<Metrics>,
+}
+
+/// The router with all middleware in front of it
+fn pipeline() -> Pipeline {
+    Pipeline::new(routes::router())
+        .with(AccessLog)
+        .with(RequestMetrics)
+        .with(Cors)
+        .with(RenderErrors)
+        .with(RateLimiter)
+        .with(Authenticate)
 }
 
 #[tokio::main]
@@ -37,7 +66,7 @@ async fn main() -> Result<(), StartupError> {
         .duplicate_to_stdout(Duplicate::Debug)
         .log_to_file()
         .directory("logs")
-        .format(colored_opt_format)
+        .format(logging::format)
         .o_timestamp(true)
         .rotate(
             Criterion::Age(Age::Day),
@@ -48,88 +77,62 @@ async fn main() -> Result<(), StartupError> {
         .map_err(|_| StartupError::NoLoggingSpec)?;
 
     //load config file
-    let config = ApiConfig::new(&env::var("CONFIG_FILE").unwrap_or("config.toml".to_string()))?;
+    let mut config = ApiConfig::new(&env::var("CONFIG_FILE").unwrap_or("config.toml".to_string()))?;
+    //containers set this, loopback isn't reachable from outside of them
+    if let Ok(bind) = env::var("BIND") {
+        config.bind = bind.split(',').map(|address| address.trim().to_string()).collect();
+    }
     info!("Config file loaded!");
 
-    let redis_link = RedisLink::new(&config).await?;
+    let metrics = Arc::new(Metrics::new());
+    let redis_link = RedisLink::new(&config, metrics.clone()).await?;
     info!("Redis connection established");
 
-    let port = config.port;
+    let token_cipher = TokenCipher::new(&config.token_encryption_key)?;
+
     let https = HttpsConnector::new();
     let client = Client::builder().build::<_, hyper::Body>(https);
-    let api_context = Arc::new(ApiContext { config, redis_link, client });
-    let addr = SocketAddr::from(([127, 0, 0, 1], port));
-    let make_svc = make_service_fn(|_conn| {
-        let context = api_context.clone();
-        async move {
-            Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
-                let context = context.clone();
-                async move { handle_request(req, context).await }
-            }))
-        }
-    });
-
-    let server = Server::bind(&addr).serve(make_svc);
+    let (revoked_sessions, _) = broadcast::channel(20);
+    let (stop, shutdown) = ShutdownSignal::new();
+    let connections = Arc::new(ConnectionTracker::default());
+    let api_context = Arc::new(ApiContext { config, redis_link, client, token_cipher, revoked_sessions, shutdown, connections, metrics });
+    routes::health::watch_bot(api_context.clone(), api_context.shutdown.clone());
+    let pipeline = Arc::new(pipeline());
+    let addresses = api_context.config.bind_addresses()?;
+    let servers = server::listen(api_context.clone(), pipeline, &addresses)?;
 
     log::info!(
-        "Startup complete, now listening for requests on port {}",
-        port
+        "Startup complete, now listening for requests on {}",
+        addresses.iter().map(ToString::to_string).collect::<Vec<_>>().join(", ")
     );
-
-    // Run this server for... forever!
-    if let Err(e) = server.await {
-        eprintln!("server error: {}", e);
+    let metrics = &api_context.config.metrics;
+    if metrics.enabled {
+        info!("Metrics are available on http://{}{}", metrics.bind, metrics.path);
     }
-    Ok(())
-}
-
-async fn handle_request(
-    request: Request<Body>,
-    context: Arc<ApiContext>,
-) -> Result<Response<Body>, Infallible> {
-    let mut reply = if let Some(path_and_query) = request.uri().path_and_query() {
-        let full_path = path_and_query.path().to_string();
-        let skip = usize::from(full_path.starts_with('/'));
-        let parts = full_path
-            .split('/')
-            .skip(skip)
-            .skip_while(|p| *p == "api")
-            .collect::<Vec<&str>>();
-        let method = Method::from(request.method());
-        let query = path_and_query.query();
-        let response = match (&method, parts.as_slice()) {
-            (&Method::GET, ["hello"]) => hello_world().await,
-            (&Method::GET, ["team_info"]) => team_info(context).await,
-            (&Method::GET, ["ws"]) => ws(context, request).await,
-            (&Method::GET, ["discord", "login"]) => login(context).await,
-            (&Method::GET, ["discord", "auth"]) => auth(context, query).await,
-            (&Method::GET, ["discord", "user"]) => user_info(context, request).await,
-            _ => not_found(),
-        };
 
-        let reply = match response {
-            Ok(response) => response,
-            Err(e) => {
-                if let RequestError::Server(e) = &e {
-                    error!("{}", e)
-                }
-                Response::builder()
-                    .status(e.get_status())
-                    .body(Body::from(format!("{}", e)))
-                    .unwrap()
-            }
-        };
+    shutdown::wait_for_signal().await.map_err(StartupError::Signal)?;
+    let deadline = api_context.config.shutdown_timeout;
+    info!("Shutting down, waiting up to {} seconds for requests to finish", deadline);
+    let _ = stop.broadcast(true);
 
-        info!("{} {} => {}", method, full_path, reply.status());
-
-        reply
-    } else {
-        Response::new("how the hell did we get here?".into())
+    let drained = async {
+        for server in servers {
+            let _ = server.await;
+        }
+        api_context.connections.drained().await;
     };
-
-    reply
-        .headers_mut()
-        .append(ACCESS_CONTROL_ALLOW_ORIGIN, "*".parse().unwrap());
-
-    Ok(reply)
+    let deadline = Instant::now() + Duration::from_secs(deadline);
+    if timeout_at(deadline, drained).await.is_err() {
+        warn!("Not everything finished in time, shutting down anyways");
+    }
+    if timeout_at(deadline, api_context.redis_link.close()).await.is_err() {
+        warn!("Redis didn't let go in time, shutting down anyways");
+    }
+    for address in &addresses {
+        if let BindAddress::Unix(path) = address {
+            let _ = fs::remove_file(path);
+        }
+    }
+    info!("Shutdown complete");
+    Ok(())
 }
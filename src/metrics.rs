@@ -0,0 +1,224 @@
+use hyper::header::CONTENT_TYPE;
+use hyper::{Body, Method, Response, StatusCode};
+use log::error;
+use prometheus::{Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts, Registry, TextEncoder};
+use serde::Deserialize;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+pub struct MetricsConfig {
+    pub enabled: bool,
+    /// address to serve the metrics on, away from the public api
+    ///
+    /// anyone who can reach the metrics can read them, so this only listens on loopback unless told otherwise
+    pub bind: SocketAddr,
+    pub path: String,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        MetricsConfig {
+            enabled: false,
+            bind: SocketAddr::from(([127, 0, 0, 1], 9100)),
+            path: "/metrics".to_string(),
+        }
+    }
+}
+
+/// Everything we keep track of for prometheus
+pub struct Metrics {
+    registry: Registry,
+    http_requests: IntCounterVec,
+    http_duration: HistogramVec,
+    ws_connections: IntGauge,
+    ws_messages: IntCounterVec,
+    bot_duration: HistogramVec,
+    bot_timeouts: IntCounterVec,
+    bot_link_drops: IntCounter,
+    bot_unknown_replies: IntCounter,
+    bot_partial_replies: IntCounterVec,
+    stream_retries: IntCounterVec,
+    stream_expired: IntCounterVec,
+    discord_requests: IntCounterVec,
+    cache_requests: IntCounterVec,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        // these only fail on invalid names or duplicates, both are mistakes in the code below
+        let http_requests = IntCounterVec::new(
+            Opts::new("http_requests_total", "HTTP requests handled"),
+            &["route", "method", "status"],
+        ).unwrap();
+        let http_duration = HistogramVec::new(
+            HistogramOpts::new("http_request_duration_seconds", "Time spent handling HTTP requests"),
+            &["route", "method"],
+        ).unwrap();
+        let ws_connections = IntGauge::new("ws_connections_active", "Currently open websockets").unwrap();
+        let ws_messages = IntCounterVec::new(
+            Opts::new("ws_messages_total", "Websocket messages received"),
+            &["type"],
+        ).unwrap();
+        let bot_duration = HistogramVec::new(
+            HistogramOpts::new("bot_request_duration_seconds", "Time until GearBot replied to a request")
+                .buckets(vec![0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0]),
+            &["request"],
+        ).unwrap();
+        let bot_timeouts = IntCounterVec::new(
+            Opts::new("bot_request_timeouts_total", "Requests GearBot didn't reply to in time"),
+            &["request"],
+        ).unwrap();
+        let bot_link_drops = IntCounter::new("bot_link_drops_total", "Times the connection for GearBot replies dropped or failed to connect").unwrap();
+        let bot_unknown_replies = IntCounter::new("bot_unknown_replies_total", "Replies from GearBot nobody was waiting for (anymore)").unwrap();
+        let bot_partial_replies = IntCounterVec::new(
+            Opts::new("bot_partial_replies_total", "Requests to multiple clusters where only some of them answered in time"),
+            &["request"],
+        ).unwrap();
+        let stream_retries = IntCounterVec::new(
+            Opts::new("bot_stream_retries_total", "Requests put back on the request stream because the GearBot consumer that took them didn't acknowledge them in time"),
+            &["request"],
+        ).unwrap();
+        let stream_expired = IntCounterVec::new(
+            Opts::new("bot_stream_expired_total", "Requests removed from the request stream because their deadline passed"),
+            &["request"],
+        ).unwrap();
+        let discord_requests = IntCounterVec::new(
+            Opts::new("discord_requests_total", "Requests made to the discord api"),
+            &["endpoint", "status"],
+        ).unwrap();
+        let cache_requests = IntCounterVec::new(
+            Opts::new("cache_requests_total", "Lookups of cached discord data"),
+            &["cache", "result"],
+        ).unwrap();
+
+        let registry = Registry::new_custom(Some("gearbot_api".to_string()), None).unwrap();
+        registry.register(Box::new(http_requests.clone())).unwrap();
+        registry.register(Box::new(http_duration.clone())).unwrap();
+        registry.register(Box::new(ws_connections.clone())).unwrap();
+        registry.register(Box::new(ws_messages.clone())).unwrap();
+        registry.register(Box::new(bot_duration.clone())).unwrap();
+        registry.register(Box::new(bot_timeouts.clone())).unwrap();
+        registry.register(Box::new(bot_link_drops.clone())).unwrap();
+        registry.register(Box::new(bot_unknown_replies.clone())).unwrap();
+        registry.register(Box::new(bot_partial_replies.clone())).unwrap();
+        registry.register(Box::new(stream_retries.clone())).unwrap();
+        registry.register(Box::new(stream_expired.clone())).unwrap();
+        registry.register(Box::new(discord_requests.clone())).unwrap();
+        registry.register(Box::new(cache_requests.clone())).unwrap();
+
+        Metrics { registry, http_requests, http_duration, ws_connections, ws_messages, bot_duration, bot_timeouts, bot_link_drops, bot_unknown_replies, bot_partial_replies, stream_retries, stream_expired, discord_requests, cache_requests }
+    }
+
+    /// `route` is the pattern of the route, not the actual path, so ids don't all get their own series
+    pub fn http_request(&self, route: &str, method: &Method, status: StatusCode, duration: Duration) {
+        let method = method_label(method);
+        self.http_requests.with_label_values(&[route, method, status.as_str()]).inc();
+        self.http_duration.with_label_values(&[route, method]).observe(duration.as_secs_f64());
+    }
+
+    /// Counts an open websocket until the guard is dropped, even if the task handling it panics
+    pub fn ws_connection(&self) -> GaugeGuard {
+        self.ws_connections.inc();
+        GaugeGuard(self.ws_connections.clone())
+    }
+
+    pub fn ws_message(&self, kind: &str) {
+        self.ws_messages.with_label_values(&[kind]).inc();
+    }
+
+    pub fn bot_reply(&self, request: &str, duration: Duration) {
+        self.bot_duration.with_label_values(&[request]).observe(duration.as_secs_f64());
+    }
+
+    pub fn bot_timeout(&self, request: &str) {
+        self.bot_timeouts.with_label_values(&[request]).inc();
+    }
+
+    pub fn bot_link_drop(&self) {
+        self.bot_link_drops.inc();
+    }
+
+    pub fn unknown_reply(&self) {
+        self.bot_unknown_replies.inc();
+    }
+
+    pub fn bot_partial_reply(&self, request: &str) {
+        self.bot_partial_replies.with_label_values(&[request]).inc();
+    }
+
+    pub fn stream_request_retried(&self, request: &str) {
+        self.stream_retries.with_label_values(&[request]).inc();
+    }
+
+    pub fn stream_request_expired(&self, request: &str) {
+        self.stream_expired.with_label_values(&[request]).inc();
+    }
+
+    /// `status` is `None` if we never got a response
+    pub fn discord_request(&self, endpoint: &str, status: Option<StatusCode>) {
+        let status = status.as_ref().map(StatusCode::as_str).unwrap_or("error");
+        self.discord_requests.with_label_values(&[endpoint, status]).inc();
+    }
+
+    pub fn cache_lookup(&self, cache: &str, hit: bool) {
+        self.cache_requests.with_label_values(&[cache, if hit { "hit" } else { "miss" }]).inc();
+    }
+
+    /// Everything in the prometheus text format
+    pub fn render(&self) -> Response<Body> {
+        let encoder = TextEncoder::new();
+        let mut buffer = Vec::new();
+        if let Err(e) = encoder.encode(&self.registry.gather(), &mut buffer) {
+            error!("Failed to encode the metrics: {}", e);
+            return Response::builder().status(StatusCode::INTERNAL_SERVER_ERROR).body(Body::empty()).unwrap();
+        }
+        Response::builder()
+            .header(CONTENT_TYPE, encoder.format_type())
+            .body(Body::from(buffer))
+            .unwrap()
+    }
+}
+
+/// Takes one off the gauge again when dropped
+pub struct GaugeGuard(IntGauge);
+
+impl Drop for GaugeGuard {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Metrics::new()
+    }
+}
+
+/// Anyone can make up methods, those all share a label
+fn method_label(method: &Method) -> &'static str {
+    match *method {
+        Method::GET => "GET",
+        Method::HEAD => "HEAD",
+        Method::POST => "POST",
+        Method::PUT => "PUT",
+        Method::PATCH => "PATCH",
+        Method::DELETE => "DELETE",
+        Method::OPTIONS => "OPTIONS",
+        _ => "OTHER",
+    }
+}
+
+/// Label for a discord api url, without the version and with ids replaced so they don't each get their own series
+pub fn discord_endpoint(path: &str) -> String {
+    let path = path.trim_start_matches("/api/");
+    let path = match path.split_once('/') {
+        Some((version, rest)) if version.len() > 1 && version.starts_with('v') && version[1..].chars().all(|c| c.is_ascii_digit()) => rest,
+        _ => path
+    };
+    path.split('/')
+        .map(|segment| if !segment.is_empty() && segment.chars().all(|c| c.is_ascii_digit()) { "{id}" } else { segment })
+        .collect::<Vec<_>>()
+        .join("/")
+}
@@ -0,0 +1,21 @@
+use crate::ApiContext;
+use crate::middleware::{BoxFuture, Middleware, Next};
+use crate::router::HandlerResult;
+use hyper::{Body, Request};
+use log::info;
+use std::sync::Arc;
+
+/// Logs every request with the status it got
+pub struct AccessLog;
+
+impl Middleware for AccessLog {
+    fn handle<'a>(&'a self, ctx: Arc<ApiContext>, request: Request<Body>, next: Next<'a>) -> BoxFuture<'a, HandlerResult> {
+        Box::pin(async move {
+            let method = request.method().clone();
+            let path = request.uri().path().to_string();
+            let response = next.run(ctx, request).await?;
+            info!("{} {} => {}", method, path, response.status());
+            Ok(response)
+        })
+    }
+}
@@ -0,0 +1,89 @@
+use crate::{api_key, session, util, ApiContext};
+use crate::error::RequestError;
+use crate::middleware::{BoxFuture, Middleware, Next};
+use crate::models::ApiKey;
+use crate::router::{Auth, HandlerResult, RouteMatch};
+use hyper::{Body, Request};
+use std::sync::Arc;
+use twilight_model::id::GuildId;
+
+/// The user a request was made by
+#[derive(Clone, Debug)]
+pub struct AuthedUser {
+    pub user_id: u64,
+    pub credential: Credential,
+}
+
+/// How the user proved who they are
+#[derive(Clone, Debug)]
+pub enum Credential {
+    /// a dashboard session, with it's token
+    Session(String),
+    /// an api key, it's scopes were already checked against the route
+    ApiKey,
+}
+
+impl AuthedUser {
+    /// The token of the session used for this request, if it was made with one
+    pub fn session_token(&self) -> Option<&str> {
+        match &self.credential {
+            Credential::Session(token) => Some(token),
+            Credential::ApiKey => None
+        }
+    }
+}
+
+/// Figures out who made the request and makes sure they are allowed to use the route
+///
+/// The user is attached to the request so handlers can get it without having to look it up again
+pub struct Authenticate;
+
+impl Middleware for Authenticate {
+    fn handle<'a>(&'a self, ctx: Arc<ApiContext>, mut request: Request<Body>, next: Next<'a>) -> BoxFuture<'a, HandlerResult> {
+        Box::pin(async move {
+            let (auth, guild) = match next.route() {
+                RouteMatch::Found { endpoint, params, .. } => match endpoint.meta.auth {
+                    Auth::User { guild: Some(param), .. } => (endpoint.meta.auth, Some(params.get::<GuildId>(param)?)),
+                    auth => (auth, None)
+                },
+                // nothing to protect, the router will refuse it anyways
+                _ => (Auth::Public, None)
+            };
+
+            // public routes don't care who is asking, so a bad key or cookie (or redis being down) shouldn't break them
+            if matches!(auth, Auth::Public) {
+                return next.run(ctx, request).await;
+            }
+
+            let user = match get_api_key(&ctx, &request).await? {
+                Some(key) => match auth {
+                    Auth::User { access, .. } if key.allows(access, guild.map(|guild| guild.0)) =>
+                        AuthedUser { user_id: key.user_id, credential: Credential::ApiKey },
+                    _ => return Err(RequestError::Forbidden)
+                },
+                None => get_session_user(&ctx, &request).await?.ok_or(RequestError::Unauthorized)?
+            };
+            request.extensions_mut().insert(user);
+            next.run(ctx, request).await
+        })
+    }
+}
+
+/// The api key this request was made with, an invalid or expired key is refused right away
+async fn get_api_key(ctx: &Arc<ApiContext>, request: &Request<Body>) -> Result<Option<ApiKey>, RequestError> {
+    match util::get_bearer_token(request) {
+        Some(key) if key.starts_with(api_key::KEY_PREFIX) =>
+            Ok(Some(api_key::get_api_key(ctx, key).await?.ok_or(RequestError::Unauthorized)?)),
+        _ => Ok(None)
+    }
+}
+
+async fn get_session_user(ctx: &Arc<ApiContext>, request: &Request<Body>) -> Result<Option<AuthedUser>, RequestError> {
+    if let Some(token) = util::get_session_token(ctx, request) {
+        if let Some(user_id) = ctx.redis_link.get::<u64>(&format!("dash_token:{}", token)).await? {
+            session::touch_session(ctx, user_id, &token, request).await?;
+            return Ok(Some(AuthedUser { user_id, credential: Credential::Session(token) }));
+        }
+    }
+    Ok(None)
+}
@@ -0,0 +1,177 @@
+use crate::ApiContext;
+use crate::middleware::{BoxFuture, Middleware, Next, REQUEST_ID_HEADER};
+use crate::router::{HandlerResult, RouteMatch};
+use hyper::header::{HeaderValue, ACCESS_CONTROL_ALLOW_CREDENTIALS, ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_EXPOSE_HEADERS, ACCESS_CONTROL_MAX_AGE, ACCESS_CONTROL_REQUEST_METHOD, ORIGIN, VARY};
+use hyper::{Body, HeaderMap, Method, Request, Response, StatusCode};
+use serde::Deserialize;
+use std::sync::Arc;
+
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+pub struct CorsConfig {
+    /// origins (like `https://gearbot.rocks`) allowed to use the api from a browser, `*` allows everyone but browsers won't send cookies along then
+    pub allowed_origins: Vec<String>,
+    /// methods preflights get told they can use, the methods the route supports if left empty
+    pub allowed_methods: Vec<String>,
+    pub allowed_headers: Vec<String>,
+    /// how long (in seconds) browsers can cache preflight answers
+    pub max_age: Option<u32>,
+    /// if listed origins can send cookies along, needs a cookie with `same_site = "None"` when the dashboard lives on another site
+    pub allow_credentials: bool,
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        CorsConfig {
+            allowed_origins: vec!["*".to_string()],
+            allowed_methods: Vec::new(),
+            allowed_headers: vec!["Authorization".to_string(), "Content-Type".to_string(), "X-Request-Id".to_string()],
+            max_age: None,
+            allow_credentials: false,
+        }
+    }
+}
+
+impl CorsConfig {
+    /// If this origin is explicitly allowed, `*` doesn't count
+    pub fn origin_listed(&self, origin: Option<&HeaderValue>) -> bool {
+        origin.and_then(|origin| origin.to_str().ok())
+            .map(|origin| self.allowed_origins.iter().any(|allowed| allowed.trim_end_matches('/').eq_ignore_ascii_case(origin)))
+            .unwrap_or(false)
+    }
+
+    /// The allow origin header value for requests from this origin, if they are allowed at all
+    fn allow_origin(&self, origin: Option<&HeaderValue>) -> Option<HeaderValue> {
+        if let Some(origin) = origin.filter(|origin| self.origin_listed(Some(origin))) {
+            return Some(origin.clone());
+        }
+        if self.allowed_origins.iter().any(|allowed| allowed == "*") {
+            Some(HeaderValue::from_static("*"))
+        } else {
+            None
+        }
+    }
+
+    fn add_headers(&self, headers: &mut HeaderMap, allow_origin: Option<HeaderValue>) {
+        // unless everyone gets the same answer it depends on the origin, caches need to know that
+        if self.allowed_origins.iter().any(|allowed| allowed != "*") {
+            headers.append(VARY, HeaderValue::from_static("Origin"));
+        }
+        if let Some(allow_origin) = allow_origin {
+            if self.allow_credentials && allow_origin != "*" {
+                headers.insert(ACCESS_CONTROL_ALLOW_CREDENTIALS, HeaderValue::from_static("true"));
+            }
+            headers.insert(ACCESS_CONTROL_EXPOSE_HEADERS, HeaderValue::from_static(REQUEST_ID_HEADER));
+            headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, allow_origin);
+        }
+    }
+}
+
+/// Adds the CORS headers from the config to every response and answers preflights
+pub struct Cors;
+
+impl Middleware for Cors {
+    fn handle<'a>(&'a self, ctx: Arc<ApiContext>, request: Request<Body>, next: Next<'a>) -> BoxFuture<'a, HandlerResult> {
+        Box::pin(async move {
+            let allow_origin = ctx.config.cors.allow_origin(request.headers().get(ORIGIN));
+            let preflight = request.method() == Method::OPTIONS && request.headers().contains_key(ACCESS_CONTROL_REQUEST_METHOD);
+
+            let mut response = match (next.route(), allow_origin.as_ref()) {
+                (RouteMatch::Options(allowed), Some(_)) if preflight => preflight_response(&ctx.config.cors, allowed)?,
+                _ => next.run(ctx.clone(), request).await?
+            };
+            ctx.config.cors.add_headers(response.headers_mut(), allow_origin);
+            Ok(response)
+        })
+    }
+}
+
+fn preflight_response(config: &CorsConfig, allowed: &[Method]) -> HandlerResult {
+    let methods = if config.allowed_methods.is_empty() {
+        allowed.iter().map(Method::as_str).collect::<Vec<_>>().join(", ")
+    } else {
+        config.allowed_methods.join(", ")
+    };
+    let mut response = Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .header(ACCESS_CONTROL_ALLOW_METHODS, methods)
+        .header(ACCESS_CONTROL_ALLOW_HEADERS, config.allowed_headers.join(", "));
+    if let Some(max_age) = config.max_age {
+        response = response.header(ACCESS_CONTROL_MAX_AGE, max_age);
+    }
+    Ok(response.body(Body::empty())?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(origins: &[&str], allow_credentials: bool) -> CorsConfig {
+        CorsConfig {
+            allowed_origins: origins.iter().map(|origin| origin.to_string()).collect(),
+            allow_credentials,
+            ..CorsConfig::default()
+        }
+    }
+
+    /// The headers a response gets for a request from this origin
+    fn headers(config: &CorsConfig, origin: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        let origin = HeaderValue::from_static(origin);
+        config.add_headers(&mut headers, config.allow_origin(Some(&origin)));
+        headers
+    }
+
+    #[test]
+    fn listed_origins_are_echoed() {
+        let config = config(&["https://gearbot.rocks/", "https://other.gearbot.rocks"], true);
+        let headers = headers(&config, "https://gearbot.rocks");
+        assert_eq!(headers[ACCESS_CONTROL_ALLOW_ORIGIN], "https://gearbot.rocks");
+        assert_eq!(headers[VARY], "Origin");
+        assert_eq!(headers[ACCESS_CONTROL_ALLOW_CREDENTIALS], "true");
+        assert_eq!(headers[ACCESS_CONTROL_EXPOSE_HEADERS], REQUEST_ID_HEADER);
+    }
+
+    #[test]
+    fn credentials_only_when_configured() {
+        let headers = headers(&config(&["https://gearbot.rocks"], false), "https://gearbot.rocks");
+        assert_eq!(headers[ACCESS_CONTROL_ALLOW_ORIGIN], "https://gearbot.rocks");
+        assert!(!headers.contains_key(ACCESS_CONTROL_ALLOW_CREDENTIALS));
+    }
+
+    #[test]
+    fn unlisted_origins_get_nothing() {
+        let headers = headers(&config(&["https://gearbot.rocks"], true), "https://evil.example");
+        assert!(!headers.contains_key(ACCESS_CONTROL_ALLOW_ORIGIN));
+        assert!(!headers.contains_key(ACCESS_CONTROL_ALLOW_CREDENTIALS));
+        assert!(!headers.contains_key(ACCESS_CONTROL_EXPOSE_HEADERS));
+        // the answer still depends on the origin
+        assert_eq!(headers[VARY], "Origin");
+    }
+
+    #[test]
+    fn everyone_gets_a_star_without_credentials() {
+        let config = config(&["*"], true);
+        let headers = headers(&config, "https://evil.example");
+        assert_eq!(headers[ACCESS_CONTROL_ALLOW_ORIGIN], "*");
+        assert!(!headers.contains_key(ACCESS_CONTROL_ALLOW_CREDENTIALS));
+        assert!(!headers.contains_key(VARY));
+        assert!(!config.origin_listed(Some(&HeaderValue::from_static("https://evil.example"))));
+    }
+
+    #[test]
+    fn preflights_list_methods_and_headers() {
+        let mut config = config(&["https://gearbot.rocks"], true);
+        let response = preflight_response(&config, &[Method::GET, Method::HEAD, Method::OPTIONS]).unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(response.headers()[ACCESS_CONTROL_ALLOW_METHODS], "GET, HEAD, OPTIONS");
+        assert_eq!(response.headers()[ACCESS_CONTROL_ALLOW_HEADERS], "Authorization, Content-Type, X-Request-Id");
+        assert!(!response.headers().contains_key(ACCESS_CONTROL_MAX_AGE));
+
+        config.allowed_methods = vec!["GET".to_string(), "POST".to_string()];
+        config.max_age = Some(600);
+        let response = preflight_response(&config, &[Method::GET, Method::OPTIONS]).unwrap();
+        assert_eq!(response.headers()[ACCESS_CONTROL_ALLOW_METHODS], "GET, POST");
+        assert_eq!(response.headers()[ACCESS_CONTROL_MAX_AGE], "600");
+    }
+}
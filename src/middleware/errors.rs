@@ -0,0 +1,71 @@
+use crate::ApiContext;
+use crate::error::{ErrorBody, RequestError};
+use crate::middleware::{BoxFuture, Middleware, Next};
+use crate::router::HandlerResult;
+use crate::util::RequestId;
+use hyper::header::{ACCEPT, CONTENT_TYPE, RETRY_AFTER};
+use hyper::{Body, Request, Response};
+use log::error;
+use serde::Serialize;
+use std::sync::Arc;
+
+const PROBLEM_JSON: &str = "application/problem+json";
+
+/// Turns errors into json responses, so the middleware around it always gets to see a response
+pub struct RenderErrors;
+
+impl Middleware for RenderErrors {
+    fn handle<'a>(&'a self, ctx: Arc<ApiContext>, request: Request<Body>, next: Next<'a>) -> BoxFuture<'a, HandlerResult> {
+        Box::pin(async move {
+            let request_id = request.extensions().get::<RequestId>().map(|id| id.0.clone()).unwrap_or_default();
+            let problem = wants_problem_json(&request);
+            Ok(next.run(ctx, request).await.unwrap_or_else(|e| render(&e, &request_id, problem)))
+        })
+    }
+}
+
+/// Clients that ask for it get RFC 7807 problem details, with our code and request id added to them
+pub fn wants_problem_json(request: &Request<Body>) -> bool {
+    request.headers().get_all(ACCEPT).iter()
+        .filter_map(|value| value.to_str().ok())
+        .any(|value| value.contains(PROBLEM_JSON))
+}
+
+#[derive(Serialize)]
+struct Problem {
+    #[serde(rename = "type")]
+    problem_type: &'static str,
+    title: &'static str,
+    status: u16,
+    detail: String,
+    code: &'static str,
+    request_id: String,
+}
+
+pub fn render(e: &RequestError, request_id: &str, problem: bool) -> Response<Body> {
+    if let RequestError::Server(e) = e {
+        error!("{}", e)
+    }
+    let status = e.get_status();
+    let body = ErrorBody::from_request_error(e, request_id);
+    let (content_type, json) = if problem {
+        let problem = Problem {
+            problem_type: "about:blank",
+            title: status.canonical_reason().unwrap_or("Error"),
+            status: status.as_u16(),
+            detail: body.message,
+            code: body.code,
+            request_id: body.request_id,
+        };
+        (PROBLEM_JSON, serde_json::to_string(&problem).unwrap())
+    } else {
+        ("application/json", serde_json::to_string(&body).unwrap())
+    };
+    let mut response = Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, content_type);
+    if let RequestError::TooManyRequests { retry_after } = e {
+        response = response.header(RETRY_AFTER, retry_after.to_string());
+    }
+    response.body(Body::from(json)).unwrap()
+}
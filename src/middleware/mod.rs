@@ -0,0 +1,111 @@
+mod auth;
+pub use auth::{Authenticate, AuthedUser};
+
+mod cors;
+pub use cors::{Cors, CorsConfig};
+
+mod errors;
+pub use errors::{render as render_error, wants_problem_json, RenderErrors};
+
+mod access_log;
+pub use access_log::AccessLog;
+
+mod request_metrics;
+pub use request_metrics::RequestMetrics;
+
+mod rate_limit;
+pub use rate_limit::{RateLimitConfig, RateLimiter};
+
+use crate::{logging, ApiContext};
+use crate::router::{allow_response, HandlerResult, RouteMatch, Router};
+use crate::error::RequestError;
+use crate::util::RequestId;
+use hyper::header::HeaderValue;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+
+/// Header to pass along the id of a request, generated if the client didn't send one
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Something that wraps around every request, like logging or authentication
+pub trait Middleware: Send + Sync {
+    /// Handles the request, usually by passing it on to `next` and doing something with the response
+    fn handle<'a>(&'a self, ctx: Arc<ApiContext>, request: Request<Body>, next: Next<'a>) -> BoxFuture<'a, HandlerResult>;
+}
+
+/// The rest of the pipeline, ending with the route the request is for
+pub struct Next<'a> {
+    middleware: &'a [Box<dyn Middleware>],
+    route: RouteMatch<'a>,
+}
+
+impl<'a> Next<'a> {
+    pub fn route(&self) -> &RouteMatch<'a> {
+        &self.route
+    }
+
+    pub fn run(self, ctx: Arc<ApiContext>, request: Request<Body>) -> BoxFuture<'a, HandlerResult> {
+        match self.middleware.split_first() {
+            Some((middleware, rest)) => middleware.handle(ctx, request, Next { middleware: rest, route: self.route }),
+            None => Box::pin(dispatch(ctx, request, self.route))
+        }
+    }
+}
+
+async fn dispatch(ctx: Arc<ApiContext>, request: Request<Body>, route: RouteMatch<'_>) -> HandlerResult {
+    match route {
+        RouteMatch::Found { endpoint, params, .. } => endpoint.call(ctx, request, params).await,
+        RouteMatch::Options(allowed) => allow_response(StatusCode::NO_CONTENT, &allowed),
+        RouteMatch::MethodNotAllowed(allowed) => allow_response(StatusCode::METHOD_NOT_ALLOWED, &allowed),
+        RouteMatch::NotFound => Err(RequestError::NotFound),
+    }
+}
+
+/// The router with all middleware in front of it, middleware added first sees the request first
+pub struct Pipeline {
+    router: Router,
+    middleware: Vec<Box<dyn Middleware>>,
+}
+
+impl Pipeline {
+    pub fn new(router: Router) -> Self {
+        Pipeline { router, middleware: Vec::new() }
+    }
+
+    pub fn with<M: Middleware + 'static>(mut self, middleware: M) -> Self {
+        self.middleware.push(Box::new(middleware));
+        self
+    }
+
+    pub async fn handle(&self, ctx: Arc<ApiContext>, mut request: Request<Body>) -> Response<Body> {
+        let request_id = request.headers().get(REQUEST_ID_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .filter(|value| logging::valid_request_id(value))
+            .map(str::to_string)
+            .unwrap_or_else(logging::new_request_id);
+        request.extensions_mut().insert(RequestId(request_id.clone()));
+        let problem = errors::wants_problem_json(&request);
+        let method = request.method().clone();
+        let path = request.uri().path().to_string();
+        let skip = usize::from(path.starts_with('/'));
+        let parts = path
+            .split('/')
+            .skip(skip)
+            .skip_while(|p| *p == "api")
+            .collect::<Vec<&str>>();
+
+        let next = Next { middleware: &self.middleware, route: self.router.find(&method, &parts) };
+        let result = logging::with_request_id(request_id.clone(), next.run(ctx, request)).await;
+        let mut response = result.unwrap_or_else(|e| errors::render(&e, &request_id, problem));
+        if method == Method::HEAD {
+            *response.body_mut() = Body::empty();
+        }
+        // only valid header values make it this far
+        response.headers_mut().insert(REQUEST_ID_HEADER, HeaderValue::from_str(&request_id).unwrap());
+        response
+    }
+}
@@ -0,0 +1,80 @@
+use crate::{util, ApiContext};
+use crate::error::RequestError;
+use crate::middleware::{BoxFuture, Middleware, Next};
+use crate::router::{HandlerResult, RateLimit, RouteMatch};
+use hyper::{Body, Request};
+use serde::Deserialize;
+use std::sync::Arc;
+
+/// How many requests a client can make per window
+#[derive(Deserialize, Debug, Clone, Copy)]
+pub struct Limit {
+    pub requests: u64,
+    /// length of the window in seconds
+    pub window: u32,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+pub struct RateLimitConfig {
+    pub enabled: bool,
+    pub default: Limit,
+    /// the discord login and invite flows
+    pub login: Limit,
+    /// routes that need to ask the bot for something
+    pub bot: Limit,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        RateLimitConfig {
+            enabled: false,
+            default: Limit { requests: 120, window: 60 },
+            login: Limit { requests: 10, window: 60 },
+            bot: Limit { requests: 30, window: 60 },
+        }
+    }
+}
+
+impl RateLimitConfig {
+    fn limit(&self, bucket: RateLimit) -> Option<(&'static str, Limit)> {
+        match bucket {
+            RateLimit::Default => Some(("default", self.default)),
+            RateLimit::Login => Some(("login", self.login)),
+            RateLimit::Bot => Some(("bot", self.bot)),
+            RateLimit::Exempt => None,
+        }
+    }
+}
+
+/// Counts requests per client and rate limit bucket of the route, refusing them once the client went over the limit
+///
+/// The counters live in redis so all instances share them. When redis fails requests are let through, no need to take down routes that don't need it
+///
+/// Off unless turned on in the config: behind a proxy without `trust_forwarded_for` everyone would share the proxy's counts
+pub struct RateLimiter;
+
+impl Middleware for RateLimiter {
+    fn handle<'a>(&'a self, ctx: Arc<ApiContext>, request: Request<Body>, next: Next<'a>) -> BoxFuture<'a, HandlerResult> {
+        Box::pin(async move {
+            let config = &ctx.config.rate_limits;
+            let limit = match next.route() {
+                RouteMatch::Found { endpoint, .. } if config.enabled => config.limit(endpoint.meta.rate_limit),
+                // unknown routes and preflights don't do anything worth limiting
+                _ => None
+            };
+            // without an address (unix sockets) there is no way to tell clients apart
+            if let (Some((name, limit)), Some(ip)) = (limit, util::get_client_ip(&ctx, &request)) {
+                let window = u64::from(limit.window.max(1));
+                let now = util::now();
+                let key = format!("rate_limit:{}:{}:{}", name, ip, now / window);
+                match ctx.redis_link.increment(&key, window as u32).await {
+                    Ok(count) if count > limit.requests => return Err(RequestError::TooManyRequests { retry_after: window - now % window }),
+                    Ok(_) => {}
+                    Err(e) => log::warn!("Unable to count a request towards the rate limit: {}", e)
+                }
+            }
+            next.run(ctx, request).await
+        })
+    }
+}
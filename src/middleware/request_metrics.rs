@@ -0,0 +1,30 @@
+use crate::ApiContext;
+use crate::middleware::{BoxFuture, Middleware, Next};
+use crate::router::{HandlerResult, RouteMatch};
+use hyper::{Body, Request};
+use std::sync::Arc;
+use std::time::Instant;
+
+/// Counts and times requests per route
+pub struct RequestMetrics;
+
+impl Middleware for RequestMetrics {
+    fn handle<'a>(&'a self, ctx: Arc<ApiContext>, request: Request<Body>, next: Next<'a>) -> BoxFuture<'a, HandlerResult> {
+        Box::pin(async move {
+            let route = match next.route() {
+                RouteMatch::Found { pattern, .. } => *pattern,
+                // made up paths would each get their own series otherwise
+                _ => "unmatched"
+            };
+            let method = request.method().clone();
+            let start = Instant::now();
+            let result = next.run(ctx.clone(), request).await;
+            let status = match &result {
+                Ok(response) => response.status(),
+                Err(e) => e.get_status()
+            };
+            ctx.metrics.http_request(route, &method, status, start.elapsed());
+            result
+        })
+    }
+}
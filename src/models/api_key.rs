@@ -0,0 +1,114 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Access {
+    Read,
+    Write,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct ApiKeyScope {
+    /// guild this scope applies to, all guilds the user has access to if left out
+    ///
+    /// refused when creating keys until there are routes about a single guild
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub guild: Option<String>, //u64 but String cause js
+    pub access: Access,
+}
+
+/// A personal api key, we only know the hash of the key itself
+#[derive(Deserialize, Serialize, Debug)]
+pub struct ApiKey {
+    pub id: String,
+    pub hash: String,
+    pub user_id: u64,
+    pub name: String,
+    pub scopes: Vec<ApiKeyScope>,
+    pub created_at: u64,
+    pub expires_at: u64,
+}
+
+/// What someone has to tell us to create a new key
+#[derive(Deserialize, Debug)]
+pub struct ApiKeyRequest {
+    pub name: String,
+    pub scopes: Vec<ApiKeyScope>,
+    /// seconds until the key expires
+    pub expires_in: u32,
+}
+
+/// The public side of an api key
+#[derive(Serialize, Debug)]
+pub struct ApiKeyDescription {
+    pub id: String,
+    pub name: String,
+    pub scopes: Vec<ApiKeyScope>,
+    pub created_at: u64,
+    pub expires_at: u64,
+    /// the key itself, only included right after creating it, we can't tell anyone after that
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+}
+
+impl ApiKey {
+    /// Checks if any of the scopes of this key allow this kind of access (to this guild)
+    pub fn allows(&self, access: Access, guild: Option<u64>) -> bool {
+        self.scopes.iter().any(|scope| {
+            //write access includes read access
+            let access_ok = scope.access == Access::Write || access == Access::Read;
+            let guild_ok = match (&scope.guild, guild) {
+                (None, _) => true,
+                (Some(scope_guild), Some(guild)) => scope_guild.parse::<u64>().ok() == Some(guild),
+                //guild scoped keys can't be used for things that aren't about a guild
+                (Some(_), None) => false,
+            };
+            access_ok && guild_ok
+        })
+    }
+
+    pub fn describe(&self, key: Option<String>) -> ApiKeyDescription {
+        ApiKeyDescription {
+            id: self.id.clone(),
+            name: self.name.clone(),
+            scopes: self.scopes.clone(),
+            created_at: self.created_at,
+            expires_at: self.expires_at,
+            key,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(scopes: Vec<ApiKeyScope>) -> ApiKey {
+        ApiKey { id: "id".to_string(), hash: "hash".to_string(), user_id: 1, name: "key".to_string(), scopes, created_at: 0, expires_at: 1 }
+    }
+
+    fn scope(access: Access, guild: Option<&str>) -> ApiKeyScope {
+        ApiKeyScope { guild: guild.map(str::to_string), access }
+    }
+
+    #[test]
+    fn write_includes_read() {
+        let read = key(vec![scope(Access::Read, None)]);
+        assert!(read.allows(Access::Read, None));
+        assert!(read.allows(Access::Read, Some(5)));
+        assert!(!read.allows(Access::Write, None));
+
+        let write = key(vec![scope(Access::Write, None)]);
+        assert!(write.allows(Access::Read, None));
+        assert!(write.allows(Access::Write, Some(5)));
+    }
+
+    #[test]
+    fn guild_scopes_only_cover_their_guild() {
+        let key = key(vec![scope(Access::Write, Some("5")), scope(Access::Read, None)]);
+        assert!(key.allows(Access::Write, Some(5)));
+        assert!(!key.allows(Access::Write, Some(6)));
+        assert!(!key.allows(Access::Write, None));
+        assert!(key.allows(Access::Read, Some(6)));
+    }
+}
@@ -0,0 +1,36 @@
+use serde::{Deserialize, Serialize};
+
+/// State we hand out before sending someone to discord, tied to the pre-login cookie of the browser it was handed to
+pub trait BoundState {
+    /// value of the pre-login cookie this state was handed out to
+    fn session(&self) -> &str;
+}
+
+/// What we remember about a login attempt between sending the user off to discord and them coming back
+#[derive(Deserialize, Serialize, Debug)]
+pub struct LoginState {
+    pub session: String,
+    /// where to send them after logging in, already checked against the allowlist
+    #[serde(default)]
+    pub next: Option<String>,
+}
+
+/// What we remember about someone inviting GearBot to one of their guilds
+#[derive(Deserialize, Serialize, Debug)]
+pub struct InviteState {
+    pub session: String,
+    pub user_id: u64,
+    pub guild_id: u64,
+}
+
+impl BoundState for LoginState {
+    fn session(&self) -> &str {
+        &self.session
+    }
+}
+
+impl BoundState for InviteState {
+    fn session(&self) -> &str {
+        &self.session
+    }
+}
@@ -2,4 +2,13 @@ mod token_response;
 pub use token_response::TokenResponse;
 
 mod user_guilds;
-pub use user_guilds::UserGuild;
\ No newline at end of file
+pub use user_guilds::UserGuild;
+
+mod login_state;
+pub use login_state::{BoundState, LoginState, InviteState};
+
+mod session_info;
+pub use session_info::{SessionInfo, SessionDescription};
+
+mod api_key;
+pub use api_key::{Access, ApiKey, ApiKeyRequest};
@@ -0,0 +1,25 @@
+use serde::{Deserialize, Serialize};
+
+/// Everything we know about a dashboard session, stored per user so they can see where they are logged in
+#[derive(Deserialize, Serialize, Debug)]
+pub struct SessionInfo {
+    pub id: String,
+    pub token: String,
+    pub created_at: u64,
+    pub last_used: u64,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+}
+
+/// The public side of a session, this is what we show the user (the token stays with us)
+#[derive(Serialize, Debug)]
+pub struct SessionDescription {
+    pub id: String,
+    pub created_at: u64,
+    pub last_used: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_agent: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ip: Option<String>,
+    pub current: bool,
+}
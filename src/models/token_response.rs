@@ -1,10 +1,22 @@
 use serde::Deserialize;
+use twilight_model::id::GuildId;
 
 #[derive(Deserialize, Debug)]
+#[allow(dead_code)] // not everything discord sends us is useful to us
 pub struct TokenResponse {
     pub access_token: String,
     pub token_type: String,
     pub expires_in: u64,
     pub refresh_token: String,
-    pub scope: String
-}
\ No newline at end of file
+    pub scope: String,
+    /// the guild the bot was added to, only there when authorizing the bot scope
+    #[serde(default)]
+    pub guild: Option<TokenGuild>,
+}
+
+#[derive(Deserialize, Debug)]
+#[allow(dead_code)]
+pub struct TokenGuild {
+    pub id: GuildId,
+    pub name: String,
+}
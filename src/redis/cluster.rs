@@ -0,0 +1,93 @@
+use crate::error::StartupError;
+use serde::Deserialize;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct ClusterConfig {
+    /// how many shards GearBot runs in total
+    pub shards: u64,
+    /// the shards each cluster runs, cluster `n` gets it's requests on `api-out:{n}`
+    pub clusters: Vec<Vec<u64>>,
+}
+
+/// Which GearBot cluster handles which guild
+pub struct ClusterMap {
+    shards: u64,
+    clusters: usize,
+    /// cluster running each shard
+    by_shard: Vec<usize>,
+    /// for spreading requests any cluster can answer
+    next: AtomicUsize,
+}
+
+impl ClusterMap {
+    /// Every shard has to be run by exactly one cluster
+    pub fn new(config: &ClusterConfig) -> Result<Self, StartupError> {
+        if config.shards == 0 {
+            return Err(StartupError::InvalidConfig);
+        }
+        let mut by_shard = vec![None; config.shards as usize];
+        for (cluster, shards) in config.clusters.iter().enumerate() {
+            for shard in shards {
+                match by_shard.get_mut(*shard as usize) {
+                    Some(slot @ None) => *slot = Some(cluster),
+                    _ => return Err(StartupError::InvalidConfig)
+                }
+            }
+        }
+        let by_shard = by_shard.into_iter().collect::<Option<Vec<_>>>().ok_or(StartupError::InvalidConfig)?;
+        Ok(ClusterMap { shards: config.shards, clusters: config.clusters.len(), by_shard, next: AtomicUsize::new(0) })
+    }
+
+    pub fn clusters(&self) -> usize {
+        self.clusters
+    }
+
+    /// Same as discord: `(guild_id >> 22) % shards`
+    pub fn shard_for_guild(&self, guild_id: u64) -> u64 {
+        (guild_id >> 22) % self.shards
+    }
+
+    pub fn cluster_for_guild(&self, guild_id: u64) -> usize {
+        self.by_shard[self.shard_for_guild(guild_id) as usize]
+    }
+
+    /// Takes turns between the clusters
+    pub fn any(&self) -> usize {
+        self.next.fetch_add(1, Ordering::Relaxed) % self.clusters
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn map(shards: u64, clusters: Vec<Vec<u64>>) -> Result<ClusterMap, StartupError> {
+        ClusterMap::new(&ClusterConfig { shards, clusters })
+    }
+
+    #[test]
+    fn routes_guilds_by_shard() {
+        let map = map(4, vec![vec![0, 1], vec![2, 3]]).unwrap();
+        assert_eq!(map.clusters(), 2);
+        // shard 2 of 4
+        let guild_id = (6 << 22) | 1234;
+        assert_eq!(map.shard_for_guild(guild_id), 2);
+        assert_eq!(map.cluster_for_guild(guild_id), 1);
+        assert_eq!(map.cluster_for_guild(1 << 22), 0);
+    }
+
+    #[test]
+    fn takes_turns() {
+        let map = map(2, vec![vec![0], vec![1]]).unwrap();
+        assert_eq!((0..4).map(|_| map.any()).collect::<Vec<_>>(), vec![0, 1, 0, 1]);
+    }
+
+    #[test]
+    fn every_shard_needs_one_cluster() {
+        assert!(map(3, vec![vec![0], vec![1]]).is_err());
+        assert!(map(2, vec![vec![0, 1], vec![1]]).is_err());
+        assert!(map(2, vec![vec![0], vec![2]]).is_err());
+        assert!(map(0, Vec::new()).is_err());
+    }
+}
@@ -0,0 +1,139 @@
+use crate::error::CommunicationError;
+use crate::middleware::BoxFuture;
+use crate::redis::storage::Storage;
+use crate::redis::transport::{BotTransport, Replies};
+use crate::redis::{GearBotRequest, Reply, ReplyData, Request};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+type Script = dyn Fn(Option<usize>, &Request) -> Option<ReplyData> + Send + Sync;
+
+/// In-process stand-in for GearBot, answering requests the way the script says
+#[derive(Clone)]
+pub struct FakeBot(Arc<FakeBotState>);
+
+struct FakeBotState {
+    replies: Replies,
+    script: Box<Script>,
+    connected: AtomicBool,
+    received: Mutex<Vec<(Option<usize>, Request)>>,
+}
+
+impl FakeBot {
+    /// The script gets every request, the bot ignores requests it returns `None` for
+    pub fn new<F>(replies: Replies, script: F) -> Self
+        where F: Fn(&Request) -> Option<ReplyData> + Send + Sync + 'static {
+        Self::clustered(replies, move |_, request| script(request))
+    }
+
+    /// Like `new`, but the script also gets the cluster the request was sent to
+    pub fn clustered<F>(replies: Replies, script: F) -> Self
+        where F: Fn(Option<usize>, &Request) -> Option<ReplyData> + Send + Sync + 'static {
+        FakeBot(Arc::new(FakeBotState {
+            replies,
+            script: Box::new(script),
+            connected: AtomicBool::new(true),
+            received: Mutex::new(Vec::new()),
+        }))
+    }
+
+    /// Acts like the link to the bot went down
+    pub fn disconnect(&self) {
+        self.0.connected.store(false, Ordering::Relaxed);
+    }
+
+    /// Every request the bot got so far
+    pub fn received(&self) -> Vec<Request> {
+        self.0.received.lock().unwrap().iter().map(|(_, request)| request.clone()).collect()
+    }
+
+    /// The clusters requests were sent to, in order
+    pub fn clusters(&self) -> Vec<Option<usize>> {
+        self.0.received.lock().unwrap().iter().map(|(cluster, _)| *cluster).collect()
+    }
+}
+
+impl BotTransport for FakeBot {
+    fn send<'a>(&'a self, cluster: Option<usize>, request: &'a GearBotRequest) -> BoxFuture<'a, Result<(), CommunicationError>> {
+        Box::pin(async move {
+            self.0.received.lock().unwrap().push((cluster, request.request.clone()));
+            if let Some(data) = (self.0.script)(cluster, &request.request) {
+                self.0.replies.complete(Reply { uuid: request.uuid, data });
+            }
+            Ok(())
+        })
+    }
+
+    fn connected(&self) -> bool {
+        self.0.connected.load(Ordering::Relaxed)
+    }
+
+    fn close(&self) -> BoxFuture<'_, ()> {
+        self.disconnect();
+        Box::pin(async {})
+    }
+}
+
+/// Storage that only lives in memory, nothing expires
+#[derive(Default)]
+pub struct MemoryStorage {
+    values: Mutex<HashMap<String, Vec<u8>>>,
+    hashes: Mutex<HashMap<String, HashMap<String, Vec<u8>>>>,
+}
+
+impl Storage for MemoryStorage {
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Option<Vec<u8>>, darkredis::Error>> {
+        let value = self.values.lock().unwrap().get(key).cloned();
+        Box::pin(async move { Ok(value) })
+    }
+
+    fn set<'a>(&'a self, key: &'a str, value: String, _expiry: Option<u32>) -> BoxFuture<'a, Result<(), darkredis::Error>> {
+        self.values.lock().unwrap().insert(key.to_string(), value.into_bytes());
+        Box::pin(async { Ok(()) })
+    }
+
+    fn delete<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<(), darkredis::Error>> {
+        self.values.lock().unwrap().remove(key);
+        self.hashes.lock().unwrap().remove(key);
+        Box::pin(async { Ok(()) })
+    }
+
+    fn expire<'a>(&'a self, _key: &'a str, _seconds: u32) -> BoxFuture<'a, Result<(), darkredis::Error>> {
+        Box::pin(async { Ok(()) })
+    }
+
+    fn increment<'a>(&'a self, key: &'a str, _expiry: u32) -> BoxFuture<'a, Result<u64, darkredis::Error>> {
+        let mut values = self.values.lock().unwrap();
+        let value = values.entry(key.to_string()).or_insert_with(|| b"0".to_vec());
+        let count = String::from_utf8_lossy(value).parse::<u64>().unwrap_or(0) + 1;
+        *value = count.to_string().into_bytes();
+        Box::pin(async move { Ok(count) })
+    }
+
+    fn hash_get<'a>(&'a self, key: &'a str, field: &'a str) -> BoxFuture<'a, Result<Option<Vec<u8>>, darkredis::Error>> {
+        let value = self.hashes.lock().unwrap().get(key).and_then(|hash| hash.get(field)).cloned();
+        Box::pin(async move { Ok(value) })
+    }
+
+    fn hash_values<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Vec<Vec<u8>>, darkredis::Error>> {
+        let values = self.hashes.lock().unwrap().get(key).map(|hash| hash.values().cloned().collect()).unwrap_or_default();
+        Box::pin(async move { Ok(values) })
+    }
+
+    fn hash_set<'a>(&'a self, key: &'a str, field: &'a str, value: String) -> BoxFuture<'a, Result<(), darkredis::Error>> {
+        self.hashes.lock().unwrap().entry(key.to_string()).or_default().insert(field.to_string(), value.into_bytes());
+        Box::pin(async { Ok(()) })
+    }
+
+    fn hash_delete<'a>(&'a self, key: &'a str, field: &'a str) -> BoxFuture<'a, Result<(), darkredis::Error>> {
+        if let Some(hash) = self.hashes.lock().unwrap().get_mut(key) {
+            hash.remove(field);
+        }
+        Box::pin(async { Ok(()) })
+    }
+
+    fn ping(&self) -> BoxFuture<'_, Result<(), darkredis::Error>> {
+        Box::pin(async { Ok(()) })
+    }
+}
@@ -2,19 +2,72 @@ use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 use twilight_model::user::UserFlags;
 
+pub mod cluster;
 pub mod redis_link;
+pub mod storage;
+pub mod streams;
+pub mod transport;
+#[cfg(test)]
+pub mod fake;
 
 #[derive(Debug, Serialize)]
 pub struct GearBotRequest {
     pub uuid: Uuid,
+    /// id of the api request this was made for, so both sides can log it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    /// unix timestamp after which nobody is waiting for the reply anymore
+    pub deadline: u64,
     pub request: Request,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub enum Request {
     TeamInfo,
     UserInfo(u64),
     MutualGuilds(u64),
+    /// checks if the bot is still answering, every cluster replies with `Pong`
+    Ping,
+}
+
+impl Request {
+    /// Name of the kind of request, for metrics
+    pub fn name(&self) -> &'static str {
+        match self {
+            Request::TeamInfo => "TeamInfo",
+            Request::UserInfo(_) => "UserInfo",
+            Request::MutualGuilds(_) => "MutualGuilds",
+            Request::Ping => "Ping",
+        }
+    }
+
+    /// The guild this is about, only the cluster that has the guild can answer it
+    ///
+    /// None of the requests are about a single guild yet, the ones that will be should return it here
+    pub fn guild(&self) -> Option<u64> {
+        match self {
+            Request::TeamInfo | Request::UserInfo(_) | Request::MutualGuilds(_) | Request::Ping => None,
+        }
+    }
+
+    /// If every cluster has to answer, each only knows about it's own guilds or if it's still alive
+    pub fn broadcast(&self) -> bool {
+        matches!(self, Request::MutualGuilds(_) | Request::Ping)
+    }
+}
+
+/// What came back from a request that went to multiple clusters
+#[derive(Debug)]
+pub struct Gathered<T> {
+    pub data: T,
+    /// clusters that didn't answer in time, whatever they know is missing from `data`
+    pub missing: Vec<usize>,
+}
+
+impl<T> Gathered<T> {
+    pub fn is_partial(&self) -> bool {
+        !self.missing.is_empty()
+    }
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -29,6 +82,7 @@ pub enum ReplyData {
     TeamInfo(TeamInfo),
     UserInfo(Option<UserInfo>),
     MutualGuildList(Vec<MinimalGuildInfo>),
+    Pong,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -1,31 +1,88 @@
 use crate::config::ApiConfig;
 use crate::error::{CommunicationError, StartupError, DatabaseError};
-use crate::redis::{GearBotRequest, Reply, ReplyData, Request, TeamInfo, UserInfo, MinimalGuildInfo};
-use darkredis::{Connection, ConnectionPool};
-use futures_util::StreamExt;
-use tokio::sync::broadcast;
-use tokio::time::{timeout, Duration};
+use crate::{logging, util};
+use crate::metrics::Metrics;
+use crate::redis::{Gathered, GearBotRequest, Reply, ReplyData, Request, TeamInfo, UserInfo, MinimalGuildInfo};
+use crate::redis::cluster::ClusterMap;
+use crate::redis::storage::{RedisStorage, Storage};
+use crate::redis::streams::StreamTransport;
+use crate::redis::transport::{BotTransport, PubSubTransport, Replies};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+use tokio::time::{self, timeout_at, Duration};
 use uuid::Uuid;
+use futures_util::future::FutureExt;
+use futures_util::stream::{FuturesUnordered, StreamExt};
 use serde::de::DeserializeOwned;
 use serde::Serialize;
-use twilight_model::id::UserId;
+
+/// How long (in seconds) the other clusters get to answer once one did, they usually answer at about the same time
+const STRAGGLER_WAIT: u64 = 3;
 
 pub struct RedisLink {
-    pool: ConnectionPool,
-    sender: broadcast::Sender<Reply>,
+    storage: Box<dyn Storage>,
+    transport: Box<dyn BotTransport>,
+    replies: Replies,
+    /// `None` when GearBot doesn't run in clusters
+    clusters: Option<ClusterMap>,
+    /// when the bot last answered a ping, 0 if it never did
+    last_pong: AtomicU64,
+    metrics: Arc<Metrics>,
 }
 
 impl RedisLink {
-    pub async fn new(config: &ApiConfig) -> Result<Self, StartupError> {
+    pub async fn new(config: &ApiConfig, metrics: Arc<Metrics>) -> Result<Self, StartupError> {
         let pool = darkredis::ConnectionPool::create(config.redis.to_string(), None, 5).await?;
-        let (sender, _) = broadcast::channel(5);
-        let connection = pool.spawn("api_connection").await?;
-        let s = sender.clone();
-        tokio::spawn(async move {
-            establish_bot_link(s, connection).await;
-        });
+        let replies = Replies::new(metrics.clone());
+        let transport: Box<dyn BotTransport> = match &config.streams {
+            Some(streams) => Box::new(StreamTransport::new(pool.clone(), streams.clone(), replies.clone(), metrics.clone())),
+            None => Box::new(PubSubTransport::new(pool.clone(), replies.clone(), metrics.clone())),
+        };
+        let clusters = config.clusters.as_ref().map(ClusterMap::new).transpose()?;
+        Ok(Self::from_parts(Box::new(RedisStorage(pool)), transport, replies, clusters, metrics))
+    }
+
+    /// The transport should hand it's replies to `replies`
+    pub fn from_parts(storage: Box<dyn Storage>, transport: Box<dyn BotTransport>, replies: Replies, clusters: Option<ClusterMap>, metrics: Arc<Metrics>) -> Self {
+        Self { storage, transport, replies, clusters, last_pong: AtomicU64::new(0), metrics }
+    }
+
+    /// Stops listening for replies from GearBot, anything still waiting for one fails
+    pub async fn close(&self) {
+        self.transport.close().await;
+        self.replies.clear();
+    }
+
+    /// Checks if redis still answers
+    pub async fn ping_redis(&self) -> Result<(), darkredis::Error> {
+        self.storage.ping().await
+    }
+
+    /// Asks the bot if it's still there, remembering when it last answered
+    ///
+    /// With clusters it only counts as an answer if all of them answered
+    pub async fn ping_bot(&self, max_wait: u64) -> Result<(), CommunicationError> {
+        let replies = self.get_replies(Request::Ping, Some(max_wait), None).await?;
+        if replies.data.iter().any(|reply| !matches!(reply.data, ReplyData::Pong)) {
+            return Err(CommunicationError::WrongReplyType);
+        }
+        if replies.is_partial() {
+            log::warn!("Clusters {:?} did not answer a ping", replies.missing);
+            return Err(CommunicationError::Timeout);
+        }
+        self.last_pong.store(util::now(), Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Unix timestamp of the last time the bot (every cluster of it) answered a ping
+    pub fn last_pong(&self) -> Option<u64> {
+        Some(self.last_pong.load(Ordering::Relaxed)).filter(|time| *time != 0)
+    }
 
-        Ok(Self { pool, sender })
+    /// If we are still listening for replies from the bot
+    pub fn subscriber_alive(&self) -> bool {
+        self.transport.connected()
     }
 
     pub async fn get_team_members(&self) -> Result<TeamInfo, CommunicationError> {
@@ -44,51 +101,123 @@ impl RedisLink {
         }
     }
 
-    pub async fn get_mutual_guilds(&self, user_id: u64) -> Result<Vec<MinimalGuildInfo>, CommunicationError> {
-        if let ReplyData::MutualGuildList(info) = self.get_reply(Request::MutualGuilds(user_id), Some(60)).await?.data {
-            Ok(info)
-        } else {
-            Err(CommunicationError::WrongReplyType)
+    /// Every cluster only knows about it's own guilds, so this combines all of their lists
+    pub async fn get_mutual_guilds(&self, user_id: u64) -> Result<Gathered<Vec<MinimalGuildInfo>>, CommunicationError> {
+        let replies = self.get_replies(Request::MutualGuilds(user_id), Some(60), Some(STRAGGLER_WAIT)).await?;
+        let mut guilds = Vec::new();
+        for reply in replies.data {
+            if let ReplyData::MutualGuildList(info) = reply.data {
+                guilds.extend(info);
+            } else {
+                return Err(CommunicationError::WrongReplyType);
+            }
+        }
+        Ok(Gathered { data: guilds, missing: replies.missing })
+    }
+
+    /// The cluster that has the guild, `None` when GearBot doesn't run in clusters
+    pub fn cluster_for_guild(&self, guild_id: u64) -> Option<usize> {
+        self.clusters.as_ref().map(|clusters| clusters.cluster_for_guild(guild_id))
+    }
+
+    /// Where a request has to go
+    fn destinations(&self, request: &Request) -> Vec<Option<usize>> {
+        match &self.clusters {
+            None => vec![None],
+            Some(clusters) if request.broadcast() => (0..clusters.clusters()).map(Some).collect(),
+            Some(clusters) => match request.guild() {
+                Some(guild_id) => vec![Some(clusters.cluster_for_guild(guild_id))],
+                None => vec![Some(clusters.any())]
+            }
         }
     }
 
+    /// For requests only one cluster answers
     async fn get_reply(
         &self,
         request: Request,
         max_wait: Option<u64>,
     ) -> Result<Reply, CommunicationError> {
-        let max_wait = max_wait.unwrap_or(60);
-        let uuid = Uuid::new_v4();
+        // only one cluster gets these, and nobody answering is an error
+        Ok(self.get_replies(request, max_wait, None).await?.data.remove(0))
+    }
 
-        let request = GearBotRequest { uuid, request };
+    /// Sends the request everywhere it has to go, and collects what comes back until everyone answered or the time is up
+    ///
+    /// Once the first reply is in, the others get at most `straggler_wait` seconds more, so one silent cluster doesn't hold everything up.
+    /// Only fails if nobody answered, the clusters that didn't are in `missing`
+    pub async fn get_replies(
+        &self,
+        request: Request,
+        max_wait: Option<u64>,
+        straggler_wait: Option<u64>,
+    ) -> Result<Gathered<Vec<Reply>>, CommunicationError> {
+        // without a subscriber the reply would never reach us
+        if !self.subscriber_alive() {
+            return Err(CommunicationError::BotLinkDown);
+        }
+        let max_wait = max_wait.unwrap_or(60);
+        let name = request.name();
+        let start = Instant::now();
+        let deadline = util::now() + max_wait;
+        let request_id = logging::current_request_id();
 
-        //scope for redis connection
-        {
-            let mut connection = self.pool.get().await;
-            let message = serde_json::to_vec(&request).map_err(CommunicationError::DataFormat)?;
-            connection.publish("api-out", message).await?;
+        let destinations = self.destinations(&request);
+        let mut guards = Vec::with_capacity(destinations.len());
+        let mut waiting = FuturesUnordered::new();
+        for cluster in destinations.iter().copied() {
+            // every cluster gets it's own uuid, so their replies don't get mixed up
+            let uuid = Uuid::new_v4();
+            let request = GearBotRequest { uuid, request_id: request_id.clone(), deadline, request: request.clone() };
+            // registered before sending, the reply could be faster then us otherwise
+            let (guard, receiver) = self.replies.register(uuid);
+            guards.push(guard);
+            waiting.push(receiver.map(move |reply| (cluster, reply)));
+            self.transport.send(cluster, &request).await?;
         }
 
-        timeout(Duration::from_secs(max_wait), self.await_reply(uuid))
-            .await
-            .map_err(|_| CommunicationError::Timeout)
-    }
-    async fn await_reply(&self, uuid: Uuid) -> Reply {
-        while let Ok(reply) = self.sender.subscribe().recv().await {
-            if reply.uuid == uuid {
-                return reply;
+        let mut deadline = time::Instant::now() + Duration::from_secs(max_wait);
+        let mut replies = Vec::with_capacity(destinations.len());
+        let mut answered = Vec::with_capacity(destinations.len());
+        // ends when everyone answered or time is up
+        while let Ok(Some((cluster, reply))) = timeout_at(deadline, waiting.next()).await {
+            match reply {
+                Ok(reply) => {
+                    if replies.is_empty() {
+                        if let Some(wait) = straggler_wait {
+                            deadline = deadline.min(time::Instant::now() + Duration::from_secs(wait));
+                        }
+                    }
+                    replies.push(reply);
+                    answered.push(cluster);
+                }
+                // the transport dropped everything that was waiting
+                Err(_) => return Err(CommunicationError::BotLinkDown)
             }
         }
-        unreachable!()
+        let missing = destinations.into_iter()
+            .filter(|cluster| !answered.contains(cluster))
+            .flatten()
+            .collect::<Vec<_>>();
+
+        if replies.is_empty() {
+            self.metrics.bot_timeout(name);
+            Err(CommunicationError::Timeout)
+        } else if !missing.is_empty() {
+            log::warn!("Clusters {:?} did not answer {} in time", missing, name);
+            self.metrics.bot_partial_reply(name);
+            Ok(Gathered { data: replies, missing })
+        } else {
+            self.metrics.bot_reply(name, start.elapsed());
+            Ok(Gathered { data: replies, missing })
+        }
     }
 
     /// Retrieves a value from Redis.
     ///
     /// Returns `None` if the key didn't exist.
     pub async fn get<D: DeserializeOwned>(&self, key: &str) -> Result<Option<D>, DatabaseError> {
-        let mut conn = self.pool.get().await;
-
-        if let Some(value) = conn.get(key).await? {
+        if let Some(value) = self.storage.get(key).await? {
             let value = serde_json::from_slice(&value).map_err(DatabaseError::Deserializing)?;
             Ok(Some(value))
         } else {
@@ -100,46 +229,59 @@ impl RedisLink {
     ///
     /// The value will automatically expire at the optionally provided time.
     pub async fn set<T: Serialize>(&self, key: &str, value: &T, expiry: Option<u32>) -> Result<(), DatabaseError> {
-        let mut conn = self.pool.get().await;
-
         let data = serde_json::to_string(value).map_err(DatabaseError::Serializing)?;
-
-        match expiry {
-            Some(ttl) => conn.set_and_expire_seconds(key, data, ttl).await?,
-            None => conn.set(key, data).await?,
-        }
+        self.storage.set(key, data, expiry).await?;
 
         Ok(())
     }
 
     /// Deletes a value from Redis.
     pub async fn delete(&self, key: &str) -> Result<(), darkredis::Error> {
-        let mut conn = self.pool.get().await;
+        self.storage.delete(key).await
+    }
 
-        conn.del(key).await?;
+    /// Sets the time until a key expires.
+    pub async fn expire(&self, key: &str, seconds: u32) -> Result<(), darkredis::Error> {
+        self.storage.expire(key, seconds).await
+    }
 
-        Ok(())
+    /// Adds one to a counter, returning the new count.
+    ///
+    /// The counter is removed `expiry` seconds after it was created.
+    pub async fn increment(&self, key: &str, expiry: u32) -> Result<u64, darkredis::Error> {
+        self.storage.increment(key, expiry).await
     }
-}
 
-async fn establish_bot_link(sender: broadcast::Sender<Reply>, connection: Connection) {
-    log::debug!("establishing api connection");
-    connection
-        .subscribe(&["gearbot-out"])
-        .await
-        .unwrap()
-        .for_each(|message| async {
-            let m = message;
-            log::debug!("{}", String::from_utf8(m.message.clone()).unwrap());
-            match serde_json::from_slice(&m.message)  {
-                Ok(reply) =>
-                    {
-                        sender.send(reply);
-                    },
-                Err(e) => {log::error!("{}", e);}
-            }
+    /// Retrieves a field of a Redis hash.
+    ///
+    /// Returns `None` if the hash or field didn't exist.
+    pub async fn hash_get<D: DeserializeOwned>(&self, key: &str, field: &str) -> Result<Option<D>, DatabaseError> {
+        if let Some(value) = self.storage.hash_get(key, field).await? {
+            let value = serde_json::from_slice(&value).map_err(DatabaseError::Deserializing)?;
+            Ok(Some(value))
+        } else {
+            Ok(None)
+        }
+    }
 
-            ;
-        })
-        .await
+    /// Retrieves the values of all fields in a Redis hash.
+    pub async fn hash_values<D: DeserializeOwned>(&self, key: &str) -> Result<Vec<D>, DatabaseError> {
+        self.storage.hash_values(key).await?
+            .into_iter()
+            .map(|value| serde_json::from_slice(&value).map_err(DatabaseError::Deserializing))
+            .collect()
+    }
+
+    /// Inserts a field into a Redis hash.
+    pub async fn hash_set<T: Serialize>(&self, key: &str, field: &str, value: &T) -> Result<(), DatabaseError> {
+        let data = serde_json::to_string(value).map_err(DatabaseError::Serializing)?;
+        self.storage.hash_set(key, field, data).await?;
+
+        Ok(())
+    }
+
+    /// Deletes a field from a Redis hash.
+    pub async fn hash_delete(&self, key: &str, field: &str) -> Result<(), darkredis::Error> {
+        self.storage.hash_delete(key, field).await
+    }
 }
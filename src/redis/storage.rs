@@ -0,0 +1,94 @@
+use crate::middleware::BoxFuture;
+use darkredis::ConnectionPool;
+
+/// Where we keep our data, values are json
+///
+/// This is redis outside of tests, see `RedisLink` for the typed versions of these
+pub trait Storage: Send + Sync {
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Option<Vec<u8>>, darkredis::Error>>;
+    fn set<'a>(&'a self, key: &'a str, value: String, expiry: Option<u32>) -> BoxFuture<'a, Result<(), darkredis::Error>>;
+    fn delete<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<(), darkredis::Error>>;
+    fn expire<'a>(&'a self, key: &'a str, seconds: u32) -> BoxFuture<'a, Result<(), darkredis::Error>>;
+    /// Adds one to a counter, the counter expires `expiry` seconds after it was created
+    fn increment<'a>(&'a self, key: &'a str, expiry: u32) -> BoxFuture<'a, Result<u64, darkredis::Error>>;
+    fn hash_get<'a>(&'a self, key: &'a str, field: &'a str) -> BoxFuture<'a, Result<Option<Vec<u8>>, darkredis::Error>>;
+    fn hash_values<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Vec<Vec<u8>>, darkredis::Error>>;
+    fn hash_set<'a>(&'a self, key: &'a str, field: &'a str, value: String) -> BoxFuture<'a, Result<(), darkredis::Error>>;
+    fn hash_delete<'a>(&'a self, key: &'a str, field: &'a str) -> BoxFuture<'a, Result<(), darkredis::Error>>;
+    /// Checks if the storage still answers
+    fn ping(&self) -> BoxFuture<'_, Result<(), darkredis::Error>>;
+}
+
+pub struct RedisStorage(pub ConnectionPool);
+
+impl Storage for RedisStorage {
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Option<Vec<u8>>, darkredis::Error>> {
+        Box::pin(async move { self.0.get().await.get(key).await })
+    }
+
+    fn set<'a>(&'a self, key: &'a str, value: String, expiry: Option<u32>) -> BoxFuture<'a, Result<(), darkredis::Error>> {
+        Box::pin(async move {
+            let mut conn = self.0.get().await;
+            match expiry {
+                Some(ttl) => conn.set_and_expire_seconds(key, value, ttl).await,
+                None => conn.set(key, value).await,
+            }
+        })
+    }
+
+    fn delete<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<(), darkredis::Error>> {
+        Box::pin(async move {
+            self.0.get().await.del(key).await?;
+            Ok(())
+        })
+    }
+
+    fn expire<'a>(&'a self, key: &'a str, seconds: u32) -> BoxFuture<'a, Result<(), darkredis::Error>> {
+        Box::pin(async move {
+            self.0.get().await.expire_seconds(key, seconds).await?;
+            Ok(())
+        })
+    }
+
+    fn increment<'a>(&'a self, key: &'a str, expiry: u32) -> BoxFuture<'a, Result<u64, darkredis::Error>> {
+        Box::pin(async move {
+            let mut conn = self.0.get().await;
+            let count = conn.incr(key).await?;
+            if count == 1 {
+                conn.expire_seconds(key, expiry).await?;
+            }
+            Ok(count.max(0) as u64)
+        })
+    }
+
+    fn hash_get<'a>(&'a self, key: &'a str, field: &'a str) -> BoxFuture<'a, Result<Option<Vec<u8>>, darkredis::Error>> {
+        Box::pin(async move { self.0.get().await.hget(key, field).await })
+    }
+
+    fn hash_values<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Vec<Vec<u8>>, darkredis::Error>> {
+        Box::pin(async move {
+            Ok(self.0.get().await.hvals(key).await?
+                .into_iter()
+                .filter_map(|value| value.optional_string())
+                .collect())
+        })
+    }
+
+    fn hash_set<'a>(&'a self, key: &'a str, field: &'a str, value: String) -> BoxFuture<'a, Result<(), darkredis::Error>> {
+        Box::pin(async move {
+            self.0.get().await.hset(key, field, value).await?;
+            Ok(())
+        })
+    }
+
+    fn hash_delete<'a>(&'a self, key: &'a str, field: &'a str) -> BoxFuture<'a, Result<(), darkredis::Error>> {
+        Box::pin(async move {
+            self.0.get().await.hdel(key, field).await?;
+            Ok(())
+        })
+    }
+
+    fn ping(&self) -> BoxFuture<'_, Result<(), darkredis::Error>> {
+        Box::pin(async move { self.0.get().await.ping().await })
+    }
+}
@@ -0,0 +1,540 @@
+use crate::error::CommunicationError;
+use crate::metrics::Metrics;
+use crate::middleware::BoxFuture;
+use crate::redis::transport::{BotTransport, Replies, MAX_BACKOFF, MIN_BACKOFF};
+use crate::redis::{GearBotRequest, Reply};
+use crate::shutdown::ShutdownSignal;
+use crate::util;
+use darkredis::{Command, ConnectionPool, Value};
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio::time::{delay_for, Duration};
+use uuid::Uuid;
+
+/// How long (in milliseconds) a single read waits for new replies
+const READ_BLOCK: &str = "1000";
+/// Most replies handled in one go
+const READ_COUNT: &str = "100";
+/// How long (in seconds) the reply stream of an instance that went away without cleaning up sticks around
+const REPLY_STREAM_EXPIRY: u32 = 3600;
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct StreamsConfig {
+    /// stream GearBot reads requests from, clusters read `{request_stream}:{cluster}`
+    pub request_stream: String,
+    /// replies come in on this stream with the id of this instance appended, so instances don't get each others replies
+    pub reply_stream: String,
+    /// consumer group GearBot reads the request streams with, created if it doesn't exist yet
+    pub group: String,
+    /// how long (in seconds) a GearBot consumer can sit on a request without acknowledging it before we put it on the stream again
+    pub retry_after: u64,
+    /// roughly how many requests the request stream keeps around
+    pub max_length: u64,
+}
+
+impl Default for StreamsConfig {
+    fn default() -> Self {
+        StreamsConfig {
+            request_stream: "api-requests".to_string(),
+            reply_stream: "api-replies".to_string(),
+            group: "gearbot".to_string(),
+            retry_after: 5,
+            max_length: 10000,
+        }
+    }
+}
+
+/// Id and fields of an entry read from a stream
+type StreamEntry = (Vec<u8>, HashMap<Vec<u8>, Vec<u8>>);
+
+/// A request that is still waiting for it's reply
+#[derive(Clone, Debug)]
+struct Outstanding {
+    stream: String,
+    /// id of the stream entry it's in, `None` while it's still being added
+    entry: Option<Vec<u8>>,
+    deadline: u64,
+    payload: Vec<u8>,
+    name: &'static str,
+}
+
+/// What maintenance has to do with the requests that are still waiting
+#[derive(Default, Debug)]
+struct Plan {
+    /// past their deadline, these are no longer outstanding
+    expired: Vec<Outstanding>,
+    /// whoever asked gave up on these before their deadline
+    abandoned: Vec<Outstanding>,
+    /// taken by a consumer that didn't acknowledge them in time
+    retry: Vec<(Uuid, Outstanding)>,
+}
+
+struct StreamState {
+    pool: ConnectionPool,
+    config: StreamsConfig,
+    reply_stream: String,
+    outstanding: Mutex<HashMap<Uuid, Outstanding>>,
+    /// request streams we made sure have the consumer group
+    groups: Mutex<HashSet<String>>,
+    replies: Replies,
+    metrics: Arc<Metrics>,
+    /// if the reader has a connection to read replies with
+    reading: AtomicBool,
+}
+
+/// Requests go on a stream with a deadline, replies come back on a stream only this instance reads
+///
+/// Unlike pub/sub nothing gets lost while GearBot restarts: requests are retried until their deadline and expired after it,
+/// and replies sent while we lost the connection are still there once it's back
+pub struct StreamTransport {
+    state: Arc<StreamState>,
+    /// tells the reader to stop
+    close: watch::Sender<bool>,
+    reader: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl StreamTransport {
+    pub fn new(pool: ConnectionPool, config: StreamsConfig, replies: Replies, metrics: Arc<Metrics>) -> Self {
+        let reply_stream = format!("{}:{}", config.reply_stream, util::random_token(8));
+        let state = Arc::new(StreamState {
+            pool,
+            config,
+            reply_stream,
+            outstanding: Mutex::new(HashMap::new()),
+            groups: Mutex::new(HashSet::new()),
+            replies,
+            metrics,
+            reading: AtomicBool::new(false),
+        });
+        let (close, closed) = ShutdownSignal::new();
+        let reader = tokio::spawn(read_replies(state.clone(), closed));
+        StreamTransport { state, close, reader: Mutex::new(Some(reader)) }
+    }
+}
+
+impl BotTransport for StreamTransport {
+    fn send<'a>(&'a self, cluster: Option<usize>, request: &'a GearBotRequest) -> BoxFuture<'a, Result<(), CommunicationError>> {
+        Box::pin(async move {
+            let payload = serde_json::to_vec(request).map_err(CommunicationError::DataFormat)?;
+            let stream = match cluster {
+                Some(cluster) => format!("{}:{}", self.state.config.request_stream, cluster),
+                None => self.state.config.request_stream.clone()
+            };
+            // in there before it's on the stream, the reply could come in before we know the entry id
+            self.state.outstanding.lock().unwrap().insert(request.uuid, Outstanding {
+                stream: stream.clone(),
+                entry: None,
+                deadline: request.deadline,
+                payload: payload.clone(),
+                name: request.request.name(),
+            });
+            let added = match self.state.ensure_group(&stream).await {
+                Ok(()) => self.state.add(&stream, &request.uuid, request.deadline, &payload).await,
+                Err(e) => Err(e)
+            };
+            match added {
+                Ok(entry) => Ok(self.state.added(&request.uuid, &stream, entry).await?),
+                Err(e) => {
+                    self.state.outstanding.lock().unwrap().remove(&request.uuid);
+                    Err(e.into())
+                }
+            }
+        })
+    }
+
+    fn connected(&self) -> bool {
+        self.state.reading.load(Ordering::Relaxed)
+    }
+
+    fn close(&self) -> BoxFuture<'_, ()> {
+        Box::pin(async move {
+            let _ = self.close.broadcast(true);
+            let reader = self.reader.lock().unwrap().take();
+            if let Some(reader) = reader {
+                let _ = reader.await;
+            }
+            // nobody will be waiting for these anymore
+            let outstanding = self.state.outstanding.lock().unwrap().drain().map(|(_, outstanding)| outstanding).collect::<Vec<_>>();
+            for outstanding in outstanding {
+                let entries = outstanding.entry.into_iter().collect::<Vec<_>>();
+                if let Err(e) = self.state.remove(&outstanding.stream, &entries).await {
+                    log::warn!("Failed to remove our requests from the request stream: {}", e);
+                    break;
+                }
+            }
+            let _ = self.state.pool.get().await.del(&self.state.reply_stream).await;
+        })
+    }
+}
+
+impl StreamState {
+    /// Puts a request on the request stream, returning the id of the entry
+    async fn add(&self, stream: &str, uuid: &Uuid, deadline: u64, payload: &[u8]) -> Result<Vec<u8>, darkredis::Error> {
+        let max_length = self.config.max_length.to_string();
+        let uuid = uuid.to_string();
+        let deadline = deadline.to_string();
+        let command = Command::new("XADD")
+            .arg(&stream)
+            .arg(&"MAXLEN").arg(&"~").arg(&max_length)
+            .arg(&"*")
+            .arg(&"uuid").arg(&uuid)
+            .arg(&"deadline").arg(&deadline)
+            .arg(&"reply_to").arg(&self.reply_stream)
+            .arg(&"request").arg(&payload);
+        match self.pool.get().await.run_command(command).await? {
+            Value::String(id) => Ok(id),
+            other => Err(darkredis::Error::UnexpectedResponse(format!("{:?}", other)))
+        }
+    }
+
+    async fn delete(&self, stream: &str, entries: &[Vec<u8>]) -> Result<(), darkredis::Error> {
+        if entries.is_empty() {
+            return Ok(());
+        }
+        let command = Command::new("XDEL").arg(&stream).args(entries);
+        self.pool.get().await.run_command(command).await?;
+        Ok(())
+    }
+
+    /// Takes requests off a request stream, also out of the pending list of whatever consumer had them
+    async fn remove(&self, stream: &str, entries: &[Vec<u8>]) -> Result<(), darkredis::Error> {
+        if entries.is_empty() {
+            return Ok(());
+        }
+        let command = Command::new("XACK").arg(&stream).arg(&self.config.group).args(entries);
+        self.pool.get().await.run_command(command).await?;
+        self.delete(stream, entries).await
+    }
+
+    /// Creates the consumer group on a request stream, unless GearBot or another api instance already did
+    async fn ensure_group(&self, stream: &str) -> Result<(), darkredis::Error> {
+        if self.groups.lock().unwrap().contains(stream) {
+            return Ok(());
+        }
+        // from the start, so nothing that was added before gets skipped
+        let command = Command::new("XGROUP").arg(&"CREATE").arg(&stream).arg(&self.config.group).arg(&"0").arg(&"MKSTREAM");
+        match self.pool.get().await.run_command(command).await {
+            Ok(_) => {}
+            Err(darkredis::Error::RedisError(e)) if e.starts_with("BUSYGROUP") => {}
+            Err(e) => return Err(e)
+        }
+        self.groups.lock().unwrap().insert(stream.to_string());
+        Ok(())
+    }
+
+    /// Remembers which entry the request is in, or removes it again if the reply already came in
+    async fn added(&self, uuid: &Uuid, stream: &str, entry: Vec<u8>) -> Result<(), darkredis::Error> {
+        let answered = match self.outstanding.lock().unwrap().get_mut(uuid) {
+            Some(outstanding) => {
+                outstanding.entry = Some(entry.clone());
+                false
+            }
+            None => true
+        };
+        if answered {
+            self.remove(stream, &[entry]).await?;
+        }
+        Ok(())
+    }
+
+    /// Entries on a request stream that a consumer took but didn't acknowledge yet, with how long ago (in milliseconds) it got them
+    async fn pending(&self, stream: &str) -> Result<HashMap<Vec<u8>, u64>, darkredis::Error> {
+        let count = self.config.max_length.to_string();
+        let command = Command::new("XPENDING").arg(&stream).arg(&self.config.group).arg(&"-").arg(&"+").arg(&count);
+        Ok(parse_pending(self.pool.get().await.run_command(command).await?))
+    }
+
+    /// Expires requests past their deadline and puts the ones a consumer sat on for too long back on the stream
+    async fn maintain(&self) -> Result<(), darkredis::Error> {
+        let streams = self.outstanding.lock().unwrap().values().map(|outstanding| outstanding.stream.clone()).collect::<HashSet<_>>();
+        let mut pending = HashMap::with_capacity(streams.len());
+        for stream in streams {
+            let entries = self.pending(&stream).await?;
+            pending.insert(stream, entries);
+        }
+        let waiting = |uuid: &Uuid| self.replies.waiting(uuid);
+        let plan = plan(&mut self.outstanding.lock().unwrap(), &pending, util::now(), self.config.retry_after, waiting);
+
+        for expired in plan.expired {
+            self.metrics.stream_request_expired(expired.name);
+            // so a bot that is catching up doesn't spend time on them
+            self.remove(&expired.stream, &expired.entry.into_iter().collect::<Vec<_>>()).await?;
+        }
+        for abandoned in plan.abandoned {
+            // nobody is going to read the reply, no point in having the bot answer it
+            self.remove(&abandoned.stream, &abandoned.entry.into_iter().collect::<Vec<_>>()).await?;
+        }
+        for (uuid, outstanding) in plan.retry {
+            // the consumer that took it probably died, a new entry gets picked up by one that didn't
+            let entry = self.add(&outstanding.stream, &uuid, outstanding.deadline, &outstanding.payload).await?;
+            self.remove(&outstanding.stream, &outstanding.entry.into_iter().collect::<Vec<_>>()).await?;
+            self.metrics.stream_request_retried(outstanding.name);
+            self.added(&uuid, &outstanding.stream, entry).await?;
+        }
+        Ok(())
+    }
+
+    /// Hands out the replies in this batch and removes them from the stream, returns the id of the last one
+    async fn handle_replies(&self, entries: Vec<StreamEntry>) -> Result<Option<Vec<u8>>, darkredis::Error> {
+        let mut ids = Vec::with_capacity(entries.len());
+        for (id, fields) in entries {
+            match fields.get(b"reply".as_ref()).map(|reply| serde_json::from_slice::<Reply>(reply)) {
+                Some(Ok(reply)) => {
+                    self.outstanding.lock().unwrap().remove(&reply.uuid);
+                    self.replies.complete(reply);
+                }
+                Some(Err(e)) => log::error!("{}", e),
+                None => log::error!("Reply stream entry {} has no reply in it", String::from_utf8_lossy(&id))
+            }
+            ids.push(id);
+        }
+        self.delete(&self.reply_stream, &ids).await?;
+        if !ids.is_empty() {
+            self.pool.get().await.expire_seconds(&self.reply_stream, REPLY_STREAM_EXPIRY).await?;
+        }
+        Ok(ids.pop())
+    }
+}
+
+/// Keeps reading replies until we get closed, reconnecting with exponential backoff whenever that fails
+async fn read_replies(state: Arc<StreamState>, closed: ShutdownSignal) {
+    let mut backoff = MIN_BACKOFF;
+    // the stream is new, so everything in it is for us
+    let mut last_id = b"0".to_vec();
+    loop {
+        let reason = match state.pool.spawn("api_replies").await {
+            Ok(mut connection) => {
+                // XREAD doesn't need the reply stream to exist and groups get made when a request stream is first used,
+                // so with the connection up we can take requests without waiting for the first read to return
+                state.reading.store(true, Ordering::Relaxed);
+                loop {
+                    let command = Command::new("XREAD")
+                        .arg(&"COUNT").arg(&READ_COUNT)
+                        .arg(&"BLOCK").arg(&READ_BLOCK)
+                        .arg(&"STREAMS").arg(&state.reply_stream).arg(&last_id);
+                    let read = tokio::select! {
+                        read = connection.run_command(command) => read,
+                        _ = closed.clone().wait() => {
+                            state.reading.store(false, Ordering::Relaxed);
+                            log::info!("Stopped reading GearBot replies");
+                            return;
+                        }
+                    };
+                    let handled = match read {
+                        Ok(value) => {
+                            backoff = MIN_BACKOFF;
+                            state.handle_replies(parse_entries(value)).await
+                        }
+                        Err(e) => break e.to_string()
+                    };
+                    match handled {
+                        Ok(Some(id)) => last_id = id,
+                        Ok(None) => {}
+                        Err(e) => break e.to_string()
+                    }
+                    if let Err(e) = state.maintain().await {
+                        break e.to_string();
+                    }
+                }
+            }
+            Err(e) => e.to_string()
+        };
+        state.reading.store(false, Ordering::Relaxed);
+        state.metrics.bot_link_drop();
+        log::warn!("Lost the connection for GearBot replies ({}), reconnecting in {}ms", reason, backoff);
+        tokio::select! {
+            _ = delay_for(Duration::from_millis(backoff)) => {},
+            _ = closed.clone().wait() => return
+        }
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+/// The entries in an XREAD reply, nothing if it timed out
+fn parse_entries(value: Value) -> Vec<StreamEntry> {
+    let mut parsed = Vec::new();
+    // [[stream, [[id, [field, value, ...]], ...]]]
+    let streams = match value {
+        Value::Array(streams) => streams,
+        _ => return parsed
+    };
+    for stream in streams {
+        let entries = match stream {
+            Value::Array(mut stream) if stream.len() == 2 => stream.pop().and_then(Value::optional_array).unwrap_or_default(),
+            _ => continue
+        };
+        for entry in entries {
+            if let Value::Array(mut entry) = entry {
+                if entry.len() != 2 {
+                    continue;
+                }
+                let fields = entry.pop().and_then(Value::optional_array).unwrap_or_default();
+                let id = match entry.pop().and_then(Value::optional_string) {
+                    Some(id) => id,
+                    None => continue
+                };
+                let mut values = HashMap::new();
+                let mut fields = fields.into_iter().filter_map(Value::optional_string);
+                while let (Some(field), Some(value)) = (fields.next(), fields.next()) {
+                    values.insert(field, value);
+                }
+                parsed.push((id, values));
+            }
+        }
+    }
+    parsed
+}
+
+/// Entry ids and idle times (in milliseconds) from an XPENDING reply
+fn parse_pending(value: Value) -> HashMap<Vec<u8>, u64> {
+    // [[id, consumer, idle, deliveries], ...]
+    value.optional_array().unwrap_or_default()
+        .into_iter()
+        .filter_map(|entry| {
+            let mut entry = entry.optional_array()?.into_iter();
+            let id = entry.next()?.optional_string()?;
+            let idle = entry.nth(1)?.optional_integer()?;
+            Some((id, idle as u64))
+        })
+        .collect()
+}
+
+/// Decides what happens to the requests that are still waiting
+///
+/// Requests only get retried while a consumer has them without acknowledging them, the ones nobody took yet are still on the stream
+/// and acknowledged ones are being answered. `pending` has the pending entries per stream, `waiting` tells if anybody still
+/// waits for the reply to a request
+fn plan(
+    outstanding: &mut HashMap<Uuid, Outstanding>,
+    pending: &HashMap<String, HashMap<Vec<u8>, u64>>,
+    now: u64,
+    retry_after: u64,
+    waiting: impl Fn(&Uuid) -> bool,
+) -> Plan {
+    let mut plan = Plan::default();
+    outstanding.retain(|uuid, outstanding| {
+        if now >= outstanding.deadline {
+            plan.expired.push(outstanding.clone());
+            return false;
+        }
+        if !waiting(uuid) {
+            plan.abandoned.push(outstanding.clone());
+            return false;
+        }
+        let idle = outstanding.entry.as_ref()
+            .and_then(|entry| pending.get(&outstanding.stream)?.get(entry));
+        if idle.is_some_and(|idle| *idle >= retry_after * 1000) {
+            plan.retry.push((*uuid, outstanding.clone()));
+        }
+        true
+    });
+    plan
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn string(value: &str) -> Value {
+        Value::String(value.as_bytes().to_vec())
+    }
+
+    fn outstanding(entry: Option<&str>, deadline: u64) -> Outstanding {
+        Outstanding {
+            stream: "api-requests".to_string(),
+            entry: entry.map(|entry| entry.as_bytes().to_vec()),
+            deadline,
+            payload: Vec::new(),
+            name: "Ping",
+        }
+    }
+
+    fn pending(entries: &[(&str, u64)]) -> HashMap<String, HashMap<Vec<u8>, u64>> {
+        let entries = entries.iter().map(|(id, idle)| (id.as_bytes().to_vec(), *idle)).collect();
+        vec![("api-requests".to_string(), entries)].into_iter().collect()
+    }
+
+    #[test]
+    fn parses_xread_replies() {
+        let value = Value::Array(vec![Value::Array(vec![
+            string("api-replies:abc"),
+            Value::Array(vec![
+                Value::Array(vec![string("1-0"), Value::Array(vec![string("reply"), string("{}")])]),
+                Value::Array(vec![string("2-0"), Value::Array(vec![string("reply"), string("[]"), string("extra")])]),
+                // not an entry
+                string("3-0"),
+            ]),
+        ])]);
+        let entries = parse_entries(value);
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].0, b"1-0");
+        assert_eq!(entries[0].1.get(b"reply".as_ref()).unwrap(), b"{}");
+        // a field without a value is dropped
+        assert_eq!(entries[1].1.len(), 1);
+    }
+
+    #[test]
+    fn parses_xread_timeouts() {
+        assert!(parse_entries(Value::Nil).is_empty());
+    }
+
+    #[test]
+    fn parses_xpending_replies() {
+        let value = Value::Array(vec![
+            Value::Array(vec![string("1-0"), string("gearbot-1"), Value::Integer(6000), Value::Integer(1)]),
+            Value::Array(vec![string("2-0"), string("gearbot-2")]),
+        ]);
+        let pending = parse_pending(value);
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending.get(b"1-0".as_ref()), Some(&6000));
+    }
+
+    #[test]
+    fn expires_past_deadline() {
+        let mut waiting = vec![(Uuid::new_v4(), outstanding(Some("1-0"), 100)), (Uuid::new_v4(), outstanding(Some("2-0"), 200))]
+            .into_iter().collect::<HashMap<_, _>>();
+        let plan = plan(&mut waiting, &HashMap::new(), 150, 5, |_| true);
+        assert_eq!(plan.expired.len(), 1);
+        assert_eq!(plan.expired[0].entry.as_deref(), Some(b"1-0".as_ref()));
+        assert!(plan.retry.is_empty());
+        assert_eq!(waiting.len(), 1);
+    }
+
+    #[test]
+    fn retries_what_a_consumer_sat_on() {
+        let stale = Uuid::new_v4();
+        let mut waiting = vec![
+            (stale, outstanding(Some("1-0"), 200)),
+            // the consumer only just got it
+            (Uuid::new_v4(), outstanding(Some("2-0"), 200)),
+            // nobody took it yet, or it was acknowledged
+            (Uuid::new_v4(), outstanding(Some("3-0"), 200)),
+            // still being added
+            (Uuid::new_v4(), outstanding(None, 200)),
+        ].into_iter().collect::<HashMap<_, _>>();
+        let plan = plan(&mut waiting, &pending(&[("1-0", 5000), ("2-0", 4999)]), 100, 5, |_| true);
+        assert!(plan.expired.is_empty());
+        assert_eq!(plan.retry.iter().map(|(uuid, _)| *uuid).collect::<Vec<_>>(), vec![stale]);
+        // retrying doesn't stop the waiting
+        assert_eq!(waiting.len(), 4);
+    }
+
+    #[test]
+    fn forgets_what_nobody_waits_for() {
+        let (kept, given_up) = (Uuid::new_v4(), Uuid::new_v4());
+        let mut waiting = vec![(kept, outstanding(Some("1-0"), 200)), (given_up, outstanding(Some("2-0"), 200))]
+            .into_iter().collect::<HashMap<_, _>>();
+        // even though a consumer sat on it, it doesn't get retried anymore
+        let plan = plan(&mut waiting, &pending(&[("2-0", 5000)]), 100, 5, |uuid| *uuid == kept);
+        assert!(plan.expired.is_empty());
+        assert!(plan.retry.is_empty());
+        assert_eq!(plan.abandoned.len(), 1);
+        assert_eq!(plan.abandoned[0].entry.as_deref(), Some(b"2-0".as_ref()));
+        assert_eq!(waiting.keys().collect::<Vec<_>>(), vec![&kept]);
+    }
+}
@@ -0,0 +1,195 @@
+use crate::error::CommunicationError;
+use crate::metrics::Metrics;
+use crate::middleware::BoxFuture;
+use crate::redis::{GearBotRequest, Reply};
+use crate::shutdown::ShutdownSignal;
+use darkredis::{Connection, ConnectionPool};
+use futures_util::StreamExt;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio::sync::{oneshot, watch};
+use tokio::task::JoinHandle;
+use tokio::time::{delay_for, Duration};
+use uuid::Uuid;
+
+/// Time (in milliseconds) before the first attempt to reconnect to redis
+pub const MIN_BACKOFF: u64 = 500;
+/// Longest time (in milliseconds) between attempts to reconnect to redis
+pub const MAX_BACKOFF: u64 = 30_000;
+
+/// How requests get to GearBot, replies are handed to the `Replies` the transport was created with
+pub trait BotTransport: Send + Sync {
+    /// `cluster` is the GearBot cluster it's for, `None` when GearBot doesn't run in clusters
+    fn send<'a>(&'a self, cluster: Option<usize>, request: &'a GearBotRequest) -> BoxFuture<'a, Result<(), CommunicationError>>;
+    /// If replies can reach us right now, requests fail right away if not
+    fn connected(&self) -> bool;
+    /// Stops receiving replies
+    fn close(&self) -> BoxFuture<'_, ()>;
+}
+
+/// Requests waiting for a reply, transports hand replies to them directly
+#[derive(Clone)]
+pub struct Replies {
+    pending: Arc<Mutex<HashMap<Uuid, oneshot::Sender<Reply>>>>,
+    metrics: Arc<Metrics>,
+}
+
+impl Replies {
+    pub fn new(metrics: Arc<Metrics>) -> Self {
+        Replies { pending: Arc::default(), metrics }
+    }
+
+    pub fn register(&self, uuid: Uuid) -> (PendingGuard<'_>, oneshot::Receiver<Reply>) {
+        let (sender, receiver) = oneshot::channel();
+        self.pending.lock().unwrap().insert(uuid, sender);
+        (PendingGuard { replies: self, uuid }, receiver)
+    }
+
+    /// Hands the reply to whoever is waiting for it
+    pub fn complete(&self, reply: Reply) {
+        let uuid = reply.uuid;
+        let delivered = match self.pending.lock().unwrap().remove(&uuid) {
+            Some(sender) => sender.send(reply).is_ok(),
+            None => false
+        };
+        //whoever asked might have timed out already, or it was for another api instance
+        if !delivered {
+            log::debug!("Received a reply for unknown request {}", uuid);
+            self.metrics.unknown_reply();
+        }
+    }
+
+    /// If somebody is still waiting for the reply to this request
+    pub fn waiting(&self, uuid: &Uuid) -> bool {
+        self.pending.lock().unwrap().contains_key(uuid)
+    }
+
+    /// Drops everything that is waiting, their replies won't come through anymore
+    pub fn clear(&self) {
+        self.pending.lock().unwrap().clear();
+    }
+}
+
+/// Removes the entry once the request is done with it, also when it timed out or got cancelled
+pub struct PendingGuard<'a> {
+    replies: &'a Replies,
+    uuid: Uuid,
+}
+
+impl Drop for PendingGuard<'_> {
+    fn drop(&mut self) {
+        self.replies.pending.lock().unwrap().remove(&self.uuid);
+    }
+}
+
+/// Requests are published on `api-out` (`api-out:{cluster}` for clusters), replies come in on `gearbot-out`
+pub struct PubSubTransport {
+    pool: ConnectionPool,
+    /// tells the subscriber to stop
+    close: watch::Sender<bool>,
+    subscriber: Mutex<Option<JoinHandle<()>>>,
+    /// if the subscriber is still listening for replies
+    subscribed: Arc<AtomicBool>,
+}
+
+impl PubSubTransport {
+    pub fn new(pool: ConnectionPool, replies: Replies, metrics: Arc<Metrics>) -> Self {
+        let (close, closed) = ShutdownSignal::new();
+        let subscribed = Arc::new(AtomicBool::new(false));
+        let subscriber = tokio::spawn(supervise_bot_link(pool.clone(), replies, closed, subscribed.clone(), metrics));
+        PubSubTransport { pool, close, subscriber: Mutex::new(Some(subscriber)), subscribed }
+    }
+}
+
+impl BotTransport for PubSubTransport {
+    fn send<'a>(&'a self, cluster: Option<usize>, request: &'a GearBotRequest) -> BoxFuture<'a, Result<(), CommunicationError>> {
+        Box::pin(async move {
+            let message = serde_json::to_vec(request).map_err(CommunicationError::DataFormat)?;
+            let channel = match cluster {
+                Some(cluster) => format!("api-out:{}", cluster),
+                None => "api-out".to_string()
+            };
+            self.pool.get().await.publish(&channel, message).await?;
+            Ok(())
+        })
+    }
+
+    fn connected(&self) -> bool {
+        self.subscribed.load(Ordering::Relaxed)
+    }
+
+    fn close(&self) -> BoxFuture<'_, ()> {
+        Box::pin(async move {
+            let _ = self.close.broadcast(true);
+            let subscriber = self.subscriber.lock().unwrap().take();
+            if let Some(subscriber) = subscriber {
+                let _ = subscriber.await;
+            }
+        })
+    }
+}
+
+/// How the subscriber stopped
+enum LinkEnd {
+    Closed,
+    Dropped(String),
+}
+
+/// Keeps a subscriber running until we get closed, reconnecting with exponential backoff whenever the connection drops
+async fn supervise_bot_link(pool: ConnectionPool, replies: Replies, closed: ShutdownSignal, subscribed: Arc<AtomicBool>, metrics: Arc<Metrics>) {
+    let mut backoff = MIN_BACKOFF;
+    loop {
+        let started = Instant::now();
+        let end = match pool.spawn("api_connection").await {
+            Ok(connection) => establish_bot_link(&replies, connection, &closed, &subscribed).await,
+            Err(e) => LinkEnd::Dropped(e.to_string())
+        };
+        subscribed.store(false, Ordering::Relaxed);
+        // replies sent while we weren't listening are lost, no point in letting those requests wait for them
+        replies.clear();
+        let reason = match end {
+            LinkEnd::Closed => break,
+            LinkEnd::Dropped(reason) => reason
+        };
+
+        metrics.bot_link_drop();
+        // a link that was up for a while dropping is a new outage, not the same one continuing
+        if started.elapsed() > Duration::from_millis(MAX_BACKOFF) {
+            backoff = MIN_BACKOFF;
+        }
+        log::warn!("Lost the connection for GearBot replies ({}), reconnecting in {}ms", reason, backoff);
+        tokio::select! {
+            _ = delay_for(Duration::from_millis(backoff)) => {},
+            _ = closed.clone().wait() => break
+        }
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+    // dropping the stream closes the connection it was using
+    log::info!("Stopped listening for GearBot replies");
+}
+
+async fn establish_bot_link(replies: &Replies, connection: Connection, closed: &ShutdownSignal, subscribed: &AtomicBool) -> LinkEnd {
+    log::debug!("establishing api connection");
+    let mut messages = match connection.subscribe(&["gearbot-out"]).await {
+        Ok(messages) => messages,
+        Err(e) => return LinkEnd::Dropped(e.to_string())
+    };
+    subscribed.store(true, Ordering::Relaxed);
+    log::info!("Listening for GearBot replies");
+    loop {
+        let m = tokio::select! {
+            message = messages.next() => match message {
+                Some(message) => message,
+                None => return LinkEnd::Dropped("connection closed".to_string())
+            },
+            _ = closed.clone().wait() => return LinkEnd::Closed
+        };
+        log::debug!("{}", String::from_utf8_lossy(&m.message));
+        match serde_json::from_slice(&m.message) {
+            Ok(reply) => replies.complete(reply),
+            Err(e) => { log::error!("{}", e); }
+        }
+    }
+}
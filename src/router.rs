@@ -0,0 +1,358 @@
+use crate::ApiContext;
+use crate::error::RequestError;
+use crate::middleware::AuthedUser;
+use crate::models::Access;
+use hyper::header::{HeaderValue, ALLOW};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use std::future::Future;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::Arc;
+use twilight_model::id::GuildId;
+
+pub type HandlerResult = Result<Response<Body>, RequestError>;
+pub type HandlerFuture = Pin<Box<dyn Future<Output = HandlerResult> + Send>>;
+type Handler = Box<dyn Fn(Arc<ApiContext>, Request<Body>, Params) -> HandlerFuture + Send + Sync>;
+
+/// Who is allowed to use a route
+#[derive(Clone, Copy, Debug)]
+pub enum Auth {
+    /// anyone can use the route, api keys don't matter
+    Public,
+    /// logged in users, api keys need a scope giving them this kind of access (to the guild in this path parameter)
+    User { access: Access, guild: Option<&'static str> },
+    /// only usable with a real dashboard session, like managing the api keys themselves
+    Session,
+}
+
+/// Which rate limit bucket requests to a route count towards
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RateLimit {
+    Default,
+    /// the discord login and invite flows
+    Login,
+    /// routes that need to ask the bot for something
+    Bot,
+    /// not counted at all, for health checks that have to keep working no matter how often they are asked
+    Exempt,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct RouteMeta {
+    pub auth: Auth,
+    pub rate_limit: RateLimit,
+}
+
+impl RouteMeta {
+    pub fn public() -> Self {
+        RouteMeta { auth: Auth::Public, rate_limit: RateLimit::Default }
+    }
+
+    pub fn user(access: Access) -> Self {
+        RouteMeta { auth: Auth::User { access, guild: None }, rate_limit: RateLimit::Default }
+    }
+
+    pub fn session() -> Self {
+        RouteMeta { auth: Auth::Session, rate_limit: RateLimit::Default }
+    }
+
+    pub fn rate_limit(mut self, rate_limit: RateLimit) -> Self {
+        self.rate_limit = rate_limit;
+        self
+    }
+}
+
+/// Types that can be captured from a path segment
+pub trait FromParam: Sized {
+    fn from_param(param: &str) -> Option<Self>;
+}
+
+impl FromParam for String {
+    fn from_param(param: &str) -> Option<Self> {
+        Some(param.to_string())
+    }
+}
+
+impl FromParam for u64 {
+    fn from_param(param: &str) -> Option<Self> {
+        u64::from_str(param).ok()
+    }
+}
+
+impl FromParam for GuildId {
+    fn from_param(param: &str) -> Option<Self> {
+        u64::from_param(param).map(GuildId)
+    }
+}
+
+/// The values captured by the `{name}` segments of a route
+#[derive(Debug, Default)]
+pub struct Params(Vec<(&'static str, String)>);
+
+impl Params {
+    /// A path that doesn't fit the type of a capture is treated like any other unknown path
+    pub fn get<T: FromParam>(&self, name: &str) -> Result<T, RequestError> {
+        self.0.iter()
+            .find(|(param, _)| *param == name)
+            .and_then(|(_, value)| T::from_param(value))
+            .ok_or(RequestError::NotFound)
+    }
+}
+
+pub struct Endpoint {
+    pub meta: RouteMeta,
+    handler: Handler,
+}
+
+impl Endpoint {
+    pub fn call(&self, ctx: Arc<ApiContext>, request: Request<Body>, params: Params) -> HandlerFuture {
+        (self.handler)(ctx, request, params)
+    }
+}
+
+enum Segment {
+    Literal(&'static str),
+    Capture(&'static str),
+}
+
+/// All endpoints living on the same path
+struct PathEntry {
+    pattern: &'static str,
+    segments: Vec<Segment>,
+    endpoints: Vec<(Method, Endpoint)>,
+}
+
+impl PathEntry {
+    fn matches(&self, path: &[&str]) -> Option<Params> {
+        if path.len() != self.segments.len() {
+            return None;
+        }
+        let mut params = Params::default();
+        for (segment, part) in self.segments.iter().zip(path) {
+            match segment {
+                Segment::Literal(literal) if literal == part => {}
+                Segment::Capture(name) if !part.is_empty() => params.0.push((name, part.to_string())),
+                _ => return None
+            }
+        }
+        Some(params)
+    }
+
+    fn endpoint(&self, method: &Method) -> Option<&Endpoint> {
+        self.endpoints.iter()
+            .find(|(endpoint_method, _)| endpoint_method == method)
+            .map(|(_, endpoint)| endpoint)
+    }
+
+    /// Methods for the allow header, GET routes also answer HEAD
+    fn methods(&self) -> Vec<Method> {
+        let mut methods = self.endpoints.iter().map(|(method, _)| method.clone()).collect::<Vec<_>>();
+        if self.endpoint(&Method::GET).is_some() && self.endpoint(&Method::HEAD).is_none() {
+            methods.push(Method::HEAD);
+        }
+        if self.endpoint(&Method::OPTIONS).is_none() {
+            methods.push(Method::OPTIONS);
+        }
+        methods
+    }
+}
+
+pub enum RouteMatch<'a> {
+    Found { pattern: &'static str, endpoint: &'a Endpoint, params: Params },
+    /// an OPTIONS request for a path without it's own OPTIONS handler
+    Options(Vec<Method>),
+    MethodNotAllowed(Vec<Method>),
+    NotFound,
+}
+
+/// Maps paths like `/guilds/{guild_id}/config` and methods to their handlers
+#[derive(Default)]
+pub struct Router {
+    paths: Vec<PathEntry>,
+}
+
+impl Router {
+    pub fn new() -> Self {
+        Router::default()
+    }
+
+    pub fn get<F, Fut>(self, pattern: &'static str, meta: RouteMeta, handler: F) -> Self
+        where F: Fn(Arc<ApiContext>, Request<Body>, Params) -> Fut + Send + Sync + 'static,
+              Fut: Future<Output = HandlerResult> + Send + 'static {
+        self.route(Method::GET, pattern, meta, handler)
+    }
+
+    pub fn post<F, Fut>(self, pattern: &'static str, meta: RouteMeta, handler: F) -> Self
+        where F: Fn(Arc<ApiContext>, Request<Body>, Params) -> Fut + Send + Sync + 'static,
+              Fut: Future<Output = HandlerResult> + Send + 'static {
+        self.route(Method::POST, pattern, meta, handler)
+    }
+
+    pub fn delete<F, Fut>(self, pattern: &'static str, meta: RouteMeta, handler: F) -> Self
+        where F: Fn(Arc<ApiContext>, Request<Body>, Params) -> Fut + Send + Sync + 'static,
+              Fut: Future<Output = HandlerResult> + Send + 'static {
+        self.route(Method::DELETE, pattern, meta, handler)
+    }
+
+    /// Adds a route, patterns are paths relative to `/api` where `{name}` segments capture that part of the path
+    pub fn route<F, Fut>(mut self, method: Method, pattern: &'static str, meta: RouteMeta, handler: F) -> Self
+        where F: Fn(Arc<ApiContext>, Request<Body>, Params) -> Fut + Send + Sync + 'static,
+              Fut: Future<Output = HandlerResult> + Send + 'static {
+        let endpoint = Endpoint {
+            meta,
+            handler: Box::new(move |ctx, request, params| Box::pin(handler(ctx, request, params))),
+        };
+        match self.paths.iter_mut().find(|entry| entry.pattern == pattern) {
+            Some(entry) => {
+                assert!(entry.endpoint(&method).is_none(), "{} {} is registered twice", method, pattern);
+                entry.endpoints.push((method, endpoint));
+            }
+            None => self.paths.push(PathEntry {
+                pattern,
+                segments: parse_pattern(pattern),
+                endpoints: vec![(method, endpoint)],
+            })
+        }
+        self
+    }
+
+    pub fn find(&self, method: &Method, path: &[&str]) -> RouteMatch<'_> {
+        let mut allowed: Vec<Method> = Vec::new();
+        for entry in &self.paths {
+            let params = match entry.matches(path) {
+                Some(params) => params,
+                None => continue
+            };
+            let endpoint = match entry.endpoint(method) {
+                // HEAD is a GET without the body, which gets dropped after the handler ran
+                None if method == Method::HEAD => entry.endpoint(&Method::GET),
+                endpoint => endpoint
+            };
+            if let Some(endpoint) = endpoint {
+                return RouteMatch::Found { pattern: entry.pattern, endpoint, params };
+            }
+            for method in entry.methods() {
+                if !allowed.contains(&method) {
+                    allowed.push(method);
+                }
+            }
+        }
+
+        if allowed.is_empty() {
+            RouteMatch::NotFound
+        } else if method == Method::OPTIONS {
+            RouteMatch::Options(allowed)
+        } else {
+            RouteMatch::MethodNotAllowed(allowed)
+        }
+    }
+}
+
+fn parse_pattern(pattern: &'static str) -> Vec<Segment> {
+    pattern.trim_start_matches('/')
+        .split('/')
+        .map(|segment| {
+            if segment.starts_with('{') && segment.ends_with('}') {
+                Segment::Capture(&segment[1..segment.len() - 1])
+            } else {
+                Segment::Literal(segment)
+            }
+        })
+        .collect()
+}
+
+/// Adapts a handler that needs the user making the request, routes using it should require one in their metadata
+pub fn authed<F, Fut>(handler: F) -> impl Fn(Arc<ApiContext>, Request<Body>, Params) -> HandlerFuture + Send + Sync + 'static
+    where F: Fn(Arc<ApiContext>, Request<Body>, Params, AuthedUser) -> Fut + Send + Sync + 'static,
+          Fut: Future<Output = HandlerResult> + Send + 'static {
+    move |ctx, request, params| {
+        // the auth middleware already refused the request if there is no user, this is just in case the metadata was forgotten
+        match request.extensions().get::<AuthedUser>().cloned() {
+            Some(user) => Box::pin(handler(ctx, request, params, user)),
+            None => Box::pin(async { Err(RequestError::Unauthorized) })
+        }
+    }
+}
+
+/// Answers OPTIONS requests and requests with the wrong method, listing the methods that can be used instead
+pub fn allow_response(status: StatusCode, allowed: &[Method]) -> HandlerResult {
+    let allow = allowed.iter().map(Method::as_str).collect::<Vec<_>>().join(", ");
+    Ok(Response::builder()
+        .status(status)
+        .header(ALLOW, HeaderValue::from_str(&allow).unwrap())
+        .body(Body::empty())?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn ok(_: Arc<ApiContext>, _: Request<Body>, _: Params) -> HandlerResult {
+        Ok(Response::new(Body::from("ok")))
+    }
+
+    fn router() -> Router {
+        Router::new()
+            .get("/guilds/{guild_id}", RouteMeta::public(), ok)
+            .delete("/guilds/{guild_id}", RouteMeta::public(), ok)
+            .post("/login", RouteMeta::public(), ok)
+    }
+
+    fn path(path: &str) -> Vec<&str> {
+        path.trim_start_matches('/').split('/').collect()
+    }
+
+    #[test]
+    fn wrong_methods_list_the_right_ones() {
+        let router = router();
+        match router.find(&Method::POST, &path("/guilds/1")) {
+            RouteMatch::MethodNotAllowed(allowed) => {
+                assert_eq!(allowed, vec![Method::GET, Method::DELETE, Method::HEAD, Method::OPTIONS]);
+                let response = allow_response(StatusCode::METHOD_NOT_ALLOWED, &allowed).unwrap();
+                assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+                assert_eq!(response.headers()[ALLOW], "GET, DELETE, HEAD, OPTIONS");
+            }
+            _ => panic!("POST /guilds/1 should not be allowed")
+        }
+        // no GET, so no HEAD either
+        assert!(matches!(router.find(&Method::GET, &path("/login")), RouteMatch::MethodNotAllowed(allowed) if allowed == vec![Method::POST, Method::OPTIONS]));
+    }
+
+    #[test]
+    fn head_falls_back_to_get() {
+        let router = router();
+        assert!(matches!(router.find(&Method::HEAD, &path("/guilds/1")), RouteMatch::Found { pattern: "/guilds/{guild_id}", .. }));
+        assert!(matches!(router.find(&Method::HEAD, &path("/login")), RouteMatch::MethodNotAllowed(_)));
+    }
+
+    #[test]
+    fn options_are_answered_automatically() {
+        let router = router();
+        assert!(matches!(router.find(&Method::OPTIONS, &path("/login")), RouteMatch::Options(allowed) if allowed == vec![Method::POST, Method::OPTIONS]));
+        assert!(matches!(router.find(&Method::OPTIONS, &path("/nothing")), RouteMatch::NotFound));
+    }
+
+    #[test]
+    fn captures_only_fit_their_type() {
+        let router = router();
+        let params = match router.find(&Method::GET, &path("/guilds/abc")) {
+            RouteMatch::Found { params, .. } => params,
+            _ => panic!("GET /guilds/abc should match the pattern")
+        };
+        assert!(matches!(params.get::<GuildId>("guild_id"), Err(RequestError::NotFound)));
+        assert_eq!(params.get::<String>("guild_id").unwrap(), "abc");
+
+        let params = match router.find(&Method::GET, &path("/guilds/1")) {
+            RouteMatch::Found { params, .. } => params,
+            _ => panic!("GET /guilds/1 should match the pattern")
+        };
+        assert_eq!(params.get::<GuildId>("guild_id").unwrap(), GuildId(1));
+        // not captured at all
+        assert!(matches!(params.get::<u64>("user_id"), Err(RequestError::NotFound)));
+    }
+
+    #[test]
+    fn empty_segments_are_not_captured() {
+        assert!(matches!(router().find(&Method::GET, &path("/guilds/")), RouteMatch::NotFound));
+    }
+}
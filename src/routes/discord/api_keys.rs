@@ -0,0 +1,40 @@
+use crate::error::{BadRequestError, RequestError};
+use crate::models::ApiKeyRequest;
+use crate::middleware::AuthedUser;
+use crate::{ApiContext, api_key};
+use hyper::{body, Body, Response, Request, StatusCode};
+use crate::router::Params;
+use std::sync::Arc;
+
+/// Lists the api keys of the current user
+pub async fn api_keys(ctx: Arc<ApiContext>, user: AuthedUser) -> Result<Response<Body>, RequestError> {
+    let keys = api_key::get_api_keys(&ctx, user.user_id).await?
+        .iter()
+        .map(|info| info.describe(None))
+        .collect::<Vec<_>>();
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .body(Body::from(serde_json::to_string(&keys).unwrap()))?)
+}
+
+/// Creates a new api key, this is the only time the key itself is shown
+pub async fn create_api_key(ctx: Arc<ApiContext>, request: Request<Body>, user: AuthedUser) -> Result<Response<Body>, RequestError> {
+    let bytes = body::to_bytes(request.into_body()).await?;
+    let key_request: ApiKeyRequest = serde_json::from_slice(&bytes).map_err(|_| BadRequestError::InvalidBody)?;
+    let (info, key) = api_key::create_api_key(&ctx, user.user_id, key_request).await?;
+    Ok(Response::builder()
+        .status(StatusCode::CREATED)
+        .body(Body::from(serde_json::to_string(&info.describe(Some(key))).unwrap()))?)
+}
+
+/// Revokes one of the api keys of the current user
+pub async fn delete_api_key(ctx: Arc<ApiContext>, params: Params, user: AuthedUser) -> Result<Response<Body>, RequestError> {
+    let id: String = params.get("id")?;
+    if api_key::revoke_api_key(&ctx, user.user_id, &id).await? {
+        Ok(Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .body(Body::empty())?)
+    } else {
+        Err(RequestError::NotFound)
+    }
+}
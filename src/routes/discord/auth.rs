@@ -1,53 +1,36 @@
 use std::sync::Arc;
-use crate::{ApiContext, util};
+use crate::{ApiContext, cookie, logging, session, util};
 use hyper::{Response, Body, Request, Method};
-use crate::error::{RequestError, BadRequestError, ServerError, CommunicationError};
-use crate::error::RequestError::BadRequest;
+use crate::error::{RequestError, BadRequestError, ServerError};
 use std::collections::HashMap;
-use hyper::header::{CONTENT_TYPE, AUTHORIZATION, LOCATION, SET_COOKIE};
+use hyper::header::{AUTHORIZATION, LOCATION, SET_COOKIE};
 use tokio_tungstenite::tungstenite::http::StatusCode;
-use crate::models::TokenResponse;
+use crate::models::LoginState;
 use hyper::body;
 use twilight_model::user::CurrentUser;
-use rand::Rng;
-use std::borrow::Borrow;
 
-pub async fn auth(ctx: Arc<ApiContext>, query: Option<&str>) -> Result<Response<Body>, RequestError> {
+pub async fn auth(ctx: Arc<ApiContext>, request: Request<Body>) -> Result<Response<Body>, RequestError> {
     //make sure we got a query as this is where the token is given by discord
-    if let Some(query) = query {
+    if let Some(query) = request.uri().query() {
+        //before we do anything, make sure this is the same browser that started the login
+        let state = form_urlencoded::parse(query.as_bytes()).find(|name| name.0 == "state").map(|state| state.1.into_owned());
+        let login_state = util::take_state::<LoginState>(&ctx, "oauth_state", &request, state).await?;
+
         //now to actually find it
         if let Some(code) = form_urlencoded::parse(query.as_bytes()).find(|name| name.0 == "code"){
             // body params
             let mut params = HashMap::with_capacity(6);
-            let id = ctx.config.application_id.to_string();
-            params.insert("client_id", id.as_str());
-            params.insert("client_secret", &ctx.config.client_secret);
             params.insert("grant_type", "authorization_code");
             params.insert("code", &code.1);
-            params.insert("redirect_uri", &ctx.config.redirect_uri);
-            params.insert("scope", "identify guilds");
-            //assemble the request
-            let request = Request::builder()
-                .method(Method::POST)
-                .uri("https://discord.com/api/oauth2/token")
-                .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
-                .body(Body::from(serde_urlencoded::to_string(params).unwrap()))?;
-
-            //make the request
-            let response = ctx.client.request(request).await?;
-            //make sure it went ok
-            if response.status() != StatusCode::OK {
-                log::error!("Discord token exchange failed with code {}: {:?}", response.status(), response.body());
-                return Err(RequestError::Server(ServerError::DiscordError("Oauth2 token exchange failed!".to_string())))
-            }
 
-            //get the entire body, no need for chunking since it's just the discord api
-            let bytes = body::to_bytes(response.into_body()).await?;
-            let info: TokenResponse = serde_json::from_slice(bytes.as_ref()).map_err(|e| RequestError::Server(ServerError::DiscordError(format!("Failed to receive token response: {}", e))))?;
+            let info = util::exchange_token(&ctx, params).await?
+                .ok_or_else(|| RequestError::Server(ServerError::DiscordError("Oauth2 token exchange failed!".to_string())))?;
 
             let token_key = format!("userid:{}", info.access_token);
             //do we already know who this token belongs to?
-            let user_id = if let Some(id) = ctx.redis_link.get::<u64>(&token_key).await? {
+            let cached = ctx.redis_link.get::<u64>(&token_key).await?;
+            ctx.metrics.cache_lookup("userid", cached.is_some());
+            let user_id = if let Some(id) = cached {
                 id
             } else {
                 //request user information from discord
@@ -56,7 +39,7 @@ pub async fn auth(ctx: Arc<ApiContext>, query: Option<&str>) -> Result<Response<
                     .uri("https://discord.com/api/v8/users/@me")
                     .header(AUTHORIZATION, format!("Bearer {}", info.access_token))
                     .body(Body::empty())?;
-                let response = ctx.client.request(request).await?;
+                let response = util::discord_request(&ctx, request).await?;
                 //make sure it went ok as well
                 if response.status() != StatusCode::OK {
                     log::error!("Discord userinfo fetch failed with code {}: {:?}", response.status(), response.body());
@@ -72,28 +55,19 @@ pub async fn auth(ctx: Arc<ApiContext>, query: Option<&str>) -> Result<Response<
 
 
             // create a session
-            let mut token = [0u8; 16];
-            rand::thread_rng().fill(&mut token);
-            let token = base64::encode(token);
-
-            ctx.redis_link.set(&format!("dash_token:{}", token), &user_id, Some(604800)).await?;
-            //if we already had an access token we overwrite it, usually gona be the same but expiry might be renewed
-            ctx.redis_link.set(&format!("access_token:{}", user_id), &info.access_token, Some(604800)).await?;
+            let token = session::create_session(&ctx, user_id, &request).await?;
+            util::store_tokens(&ctx, user_id, &info).await?;
 
             //trigger a fetch of the user guilds so we have them ready for the guild list request we will get next
-            tokio::spawn(util::get_user_guilds(ctx.clone(), user_id, info.access_token));
+            logging::spawn(util::get_user_guilds(ctx.clone(), user_id));
 
-            let (protocol, secure) = if ctx.config.secure {
-                ("https", "Secure; SameSite=Strict;")
-            } else {
-                ("http", "")
-            };
-
-            let url = format!("{}://{}/api/discord/user", protocol, ctx.config.domain);
+            let url = login_state.next.unwrap_or_else(|| format!("{}://{}/api/discord/user", ctx.config.protocol(), ctx.config.domain));
 
             Ok(Response::builder().status(StatusCode::TEMPORARY_REDIRECT)
                 .header(LOCATION, url)
-                .header(SET_COOKIE, format!("token={}; Max-Age=604800; {}; path=/", token, secure))
+                .header(SET_COOKIE, cookie::session_cookie(&ctx, &token).to_string())
+                //the login is done, the pre-login cookie has served its purpose
+                .header(SET_COOKIE, cookie::login_removal_cookie(&ctx).to_string())
                 .body(Body::empty())
                 .unwrap())
         } else {
@@ -102,4 +76,5 @@ pub async fn auth(ctx: Arc<ApiContext>, query: Option<&str>) -> Result<Response<
     } else {
         Err(RequestError::BadRequest(BadRequestError::NoAccessCode))
     }
-}
\ No newline at end of file
+}
+
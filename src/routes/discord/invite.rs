@@ -0,0 +1,126 @@
+use crate::error::{BadRequestError, DatabaseError, RequestError, ServerError};
+use crate::middleware::AuthedUser;
+use crate::models::InviteState;
+use crate::routes::discord::login::STATE_EXPIRY;
+use crate::{ApiContext, cookie, util};
+use hyper::header::{LOCATION, SET_COOKIE};
+use hyper::{Body, Request, Response, StatusCode};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::time::{delay_for, timeout, Duration};
+use twilight_model::guild::Permissions;
+
+/// How long (in seconds) we wait for GearBot to show up in the new guild before sending the user to it's dashboard anyways
+const JOIN_WAIT: u64 = 5;
+
+/// Sends the user off to discord to invite GearBot to one of their guilds
+pub async fn invite(ctx: Arc<ApiContext>, request: Request<Body>, user: AuthedUser) -> Result<Response<Body>, RequestError> {
+    let user_id = user.user_id;
+    let guild_id = request.uri().query()
+        .and_then(|query| form_urlencoded::parse(query.as_bytes()).find(|(name, _)| name == "guild_id"))
+        .and_then(|(_, value)| value.parse::<u64>().ok())
+        .ok_or(BadRequestError::InvalidGuildId)?;
+
+    //only people who can manage the guild can add bots to it, no point in sending anyone else to discord
+    let guilds = util::get_user_guilds(ctx.clone(), user_id).await?.ok_or(RequestError::Unauthorized)?;
+    let guild = guilds.iter().find(|guild| guild.id.0 == guild_id).ok_or(RequestError::NotFound)?;
+    if !guild.owner && !guild.permissions.intersects(Permissions::MANAGE_GUILD | Permissions::ADMINISTRATOR) {
+        return Err(RequestError::Forbidden);
+    }
+
+    let session = cookie::get_login_session(&request).unwrap_or_else(|| util::random_token(16));
+    let state = util::random_token(16);
+    ctx.redis_link.set(&format!("invite_state:{}", state), &InviteState { session: session.clone(), user_id, guild_id }, Some(STATE_EXPIRY)).await?;
+
+    let permissions = ctx.config.invite_permissions.unwrap_or_else(|| default_permissions().bits());
+    let params = form_urlencoded::Serializer::new(String::new())
+        .append_pair("client_id", ctx.config.application_id.to_string().as_str())
+        .append_pair("scope", "bot applications.commands")
+        .append_pair("permissions", &permissions.to_string())
+        .append_pair("guild_id", &guild_id.to_string())
+        .append_pair("disable_guild_select", "true")
+        .append_pair("redirect_uri", &ctx.config.invite_redirect_uri())
+        .append_pair("response_type", "code")
+        .append_pair("state", &state)
+        .finish();
+
+    Ok(Response::builder().status(StatusCode::TEMPORARY_REDIRECT)
+        .header(LOCATION, format!("https://discord.com/api/oauth2/authorize?{}", params))
+        .header(SET_COOKIE, cookie::login_cookie(&ctx, &session, STATE_EXPIRY).to_string())
+        .body(Body::empty())?)
+}
+
+/// Where discord sends the user back to after they invited GearBot (or changed their mind)
+pub async fn invite_callback(ctx: Arc<ApiContext>, request: Request<Body>) -> Result<Response<Body>, RequestError> {
+    let query = request.uri().query().unwrap_or("");
+    let params = form_urlencoded::parse(query.as_bytes()).into_owned().collect::<HashMap<String, String>>();
+    let invite = util::take_state::<InviteState>(&ctx, "invite_state", &request, params.get("state").cloned()).await?;
+
+    //they cancelled, send them back to where they came from
+    if params.contains_key("error") {
+        return redirect(&ctx, ctx.config.dashboard_url());
+    }
+
+    //make sure they didn't swap out the guild along the way
+    let code = params.get("code").ok_or(BadRequestError::NoAccessCode)?;
+    if params.get("guild_id").and_then(|id| id.parse::<u64>().ok()) != Some(invite.guild_id) {
+        return Err(BadRequestError::InvalidGuildId.into());
+    }
+
+    //trading in the code is what actually proves discord authorized it
+    let redirect_uri = ctx.config.invite_redirect_uri();
+    let mut grant = HashMap::with_capacity(8);
+    grant.insert("grant_type", "authorization_code");
+    grant.insert("code", code.as_str());
+    grant.insert("redirect_uri", redirect_uri.as_str());
+    grant.insert("scope", "bot applications.commands");
+    let info = util::exchange_token(&ctx, grant).await?
+        .ok_or_else(|| RequestError::Server(ServerError::DiscordError("Oauth2 bot authorization failed!".to_string())))?;
+    if info.guild.map(|guild| guild.id.0) != Some(invite.guild_id) {
+        return Err(BadRequestError::InvalidGuildId.into());
+    }
+
+    //their guild list changed, no more serving the cached one
+    ctx.redis_link.delete(&format!("guilds:{}", invite.user_id)).await.map_err(DatabaseError::from)?;
+
+    //give GearBot a moment to receive the guild so the dashboard has something to show, it keeps asking for the guild list if this wasn't enough
+    let _ = timeout(Duration::from_secs(JOIN_WAIT), wait_for_guild(&ctx, invite.user_id, invite.guild_id)).await;
+
+    redirect(&ctx, ctx.config.guild_dashboard_url(invite.guild_id))
+}
+
+async fn wait_for_guild(ctx: &Arc<ApiContext>, user_id: u64, guild_id: u64) {
+    loop {
+        if let Ok(guilds) = ctx.redis_link.get_mutual_guilds(user_id).await {
+            if guilds.data.iter().any(|guild| guild.id == guild_id) {
+                return;
+            }
+        }
+        delay_for(Duration::from_secs(1)).await;
+    }
+}
+
+fn redirect(ctx: &Arc<ApiContext>, url: String) -> Result<Response<Body>, RequestError> {
+    Ok(Response::builder().status(StatusCode::TEMPORARY_REDIRECT)
+        .header(LOCATION, url)
+        //the invite is done, the pre-login cookie has served its purpose
+        .header(SET_COOKIE, cookie::login_removal_cookie(ctx).to_string())
+        .body(Body::empty())?)
+}
+
+/// What GearBot needs to do it's job
+fn default_permissions() -> Permissions {
+    Permissions::KICK_MEMBERS
+        | Permissions::BAN_MEMBERS
+        | Permissions::ADD_REACTIONS
+        | Permissions::VIEW_AUDIT_LOG
+        | Permissions::VIEW_CHANNEL
+        | Permissions::SEND_MESSAGES
+        | Permissions::MANAGE_MESSAGES
+        | Permissions::EMBED_LINKS
+        | Permissions::ATTACH_FILES
+        | Permissions::READ_MESSAGE_HISTORY
+        | Permissions::USE_EXTERNAL_EMOJIS
+        | Permissions::MANAGE_NICKNAMES
+        | Permissions::MANAGE_ROLES
+}
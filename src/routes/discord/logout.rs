@@ -0,0 +1,35 @@
+use crate::error::RequestError;
+use crate::middleware::{self, AuthedUser};
+use crate::util::RequestId;
+use crate::{ApiContext, cookie, session, util};
+use hyper::{Body, Response, Request, StatusCode};
+use hyper::header::SET_COOKIE;
+use std::sync::Arc;
+
+/// Ends the session this request was made with
+pub async fn logout(ctx: Arc<ApiContext>, request: Request<Body>) -> Result<Response<Body>, RequestError> {
+    if let Some(token) = util::get_session_token(&ctx, &request) {
+        session::revoke_session(&ctx, &token).await?;
+    }
+    //clear the cookie regardless, no reason to hang on to a dead session
+    Ok(Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .header(SET_COOKIE, cookie::session_removal_cookie(&ctx).to_string())
+        .body(Body::empty())?)
+}
+
+/// Ends every session of the user and revokes our access to their discord account
+pub async fn logout_everywhere(ctx: Arc<ApiContext>, request: Request<Body>, user: AuthedUser) -> Result<Response<Body>, RequestError> {
+    //discord first, if that fails nothing changed yet and they can just try again
+    util::revoke_discord_tokens(&ctx, user.user_id).await?;
+    let mut response = match session::revoke_all_sessions(&ctx, user.user_id).await {
+        Ok(()) => Response::builder().status(StatusCode::NO_CONTENT).body(Body::empty())?,
+        //some sessions might be gone already, this one included
+        Err(e) => {
+            let request_id = request.extensions().get::<RequestId>().map(|id| id.0.clone()).unwrap_or_default();
+            middleware::render_error(&e.into(), &request_id, middleware::wants_problem_json(&request))
+        }
+    };
+    response.headers_mut().insert(SET_COOKIE, cookie::session_removal_cookie(&ctx).to_string().parse().unwrap());
+    Ok(response)
+}
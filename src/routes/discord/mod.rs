@@ -1,8 +1,20 @@
 mod login;
 pub use login::login;
 
+mod invite;
+pub use invite::{invite, invite_callback};
+
 mod auth;
 pub use auth::auth;
 
 mod user_info;
-pub use user_info::user_info;
\ No newline at end of file
+pub use user_info::user_info;
+
+mod logout;
+pub use logout::{logout, logout_everywhere};
+
+mod sessions;
+pub use sessions::{sessions, delete_session};
+
+mod api_keys;
+pub use api_keys::{api_keys, create_api_key, delete_api_key};
@@ -0,0 +1,38 @@
+use crate::error::RequestError;
+use crate::middleware::AuthedUser;
+use crate::models::SessionDescription;
+use crate::router::Params;
+use crate::{ApiContext, session};
+use hyper::{Body, Response, StatusCode};
+use std::sync::Arc;
+
+/// Lists all sessions of the current user
+pub async fn sessions(ctx: Arc<ApiContext>, user: AuthedUser) -> Result<Response<Body>, RequestError> {
+    let current = user.session_token().map(session::session_id);
+    let sessions = session::get_sessions(&ctx, user.user_id).await?
+        .into_iter()
+        .map(|info| SessionDescription {
+            current: current.as_ref() == Some(&info.id),
+            id: info.id,
+            created_at: info.created_at,
+            last_used: info.last_used,
+            user_agent: info.user_agent,
+            ip: info.ip,
+        })
+        .collect::<Vec<_>>();
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .body(Body::from(serde_json::to_string(&sessions).unwrap()))?)
+}
+
+/// Ends one of the sessions of the current user
+pub async fn delete_session(ctx: Arc<ApiContext>, params: Params, user: AuthedUser) -> Result<Response<Body>, RequestError> {
+    let id: String = params.get("id")?;
+    if session::revoke_session_by_id(&ctx, user.user_id, &id).await? {
+        Ok(Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .body(Body::empty())?)
+    } else {
+        Err(RequestError::NotFound)
+    }
+}
@@ -1,23 +1,14 @@
 use crate::error::RequestError;
+use crate::middleware::AuthedUser;
 use crate::ApiContext;
-use hyper::{Body, Response, Request, StatusCode};
+use hyper::{Body, Response, StatusCode};
 use std::sync::Arc;
-use hyper::header::COOKIE;
-use crate::util::get_user_id;
 
 
-pub async fn user_info(ctx: Arc<ApiContext>, request: Request<Body>) -> Result<Response<Body>, RequestError> {
-    if let Some(user_id) = get_user_id(&ctx, &request).await? {
-        //welcome authenticated user!
-        if let Some(user_info) = ctx.redis_link.get_user_info(user_id).await? {
-            return Ok(
-                Response::builder()
-                    .status(StatusCode::OK)
-                    .body(Body::from(serde_json::to_string(&user_info).unwrap()))?
-            )
-        }
-    }
-        Ok(Response::builder()
-            .status(StatusCode::UNAUTHORIZED)
-            .body(Body::empty())?)
+pub async fn user_info(ctx: Arc<ApiContext>, user: AuthedUser) -> Result<Response<Body>, RequestError> {
+    //welcome authenticated user!
+    let user_info = ctx.redis_link.get_user_info(user.user_id).await?.ok_or(RequestError::Unauthorized)?;
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .body(Body::from(serde_json::to_string(&user_info).unwrap()))?)
 }
@@ -0,0 +1,115 @@
+use crate::error::RequestError;
+use crate::shutdown::ShutdownSignal;
+use crate::{util, ApiContext};
+use hyper::header::CONTENT_TYPE;
+use hyper::{Body, Response, StatusCode};
+use serde::Serialize;
+use std::sync::Arc;
+use tokio::time::{delay_for, timeout, Duration};
+
+/// How often we ping the bot
+const PING_INTERVAL: u64 = 15;
+/// How long the bot gets to answer a ping
+const PING_TIMEOUT: u64 = 5;
+/// Longest time since the last pong before we consider the bot gone, a couple of missed pings are fine
+const PONG_MAX_AGE: u64 = 60;
+/// How long redis gets to answer before we consider it down
+const REDIS_TIMEOUT: u64 = 2;
+
+#[derive(Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Status {
+    Up,
+    Down,
+}
+
+#[derive(Serialize)]
+struct Component {
+    status: Status,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detail: Option<String>,
+}
+
+impl Component {
+    fn up(detail: Option<String>) -> Self {
+        Component { status: Status::Up, detail }
+    }
+
+    fn down(detail: String) -> Self {
+        Component { status: Status::Down, detail: Some(detail) }
+    }
+
+    fn is_up(&self) -> bool {
+        matches!(self.status, Status::Up)
+    }
+}
+
+#[derive(Serialize)]
+struct Components {
+    redis: Component,
+    /// the subscriber receiving replies from the bot
+    bot_link: Component,
+    bot: Component,
+}
+
+#[derive(Serialize)]
+struct Readiness {
+    status: Status,
+    components: Components,
+}
+
+/// If the process is running and handling requests at all
+pub async fn live() -> Result<Response<Body>, RequestError> {
+    json_response(StatusCode::OK, &Component::up(None))
+}
+
+/// If we can actually do our job, answers 503 when one of the components is down
+pub async fn ready(ctx: Arc<ApiContext>) -> Result<Response<Body>, RequestError> {
+    let redis = match timeout(Duration::from_secs(REDIS_TIMEOUT), ctx.redis_link.ping_redis()).await {
+        Ok(Ok(())) => Component::up(None),
+        Ok(Err(e)) => Component::down(e.to_string()),
+        Err(_) => Component::down("Redis did not answer in time".to_string())
+    };
+    let bot_link = if ctx.redis_link.subscriber_alive() {
+        Component::up(None)
+    } else {
+        Component::down("Not listening for replies from GearBot".to_string())
+    };
+    let bot = match ctx.redis_link.last_pong() {
+        Some(time) => {
+            let age = util::now().saturating_sub(time);
+            let detail = format!("Last answered a ping {} seconds ago", age);
+            if age <= PONG_MAX_AGE { Component::up(Some(detail)) } else { Component::down(detail) }
+        }
+        None => Component::down("GearBot never answered a ping".to_string())
+    };
+
+    let ready = redis.is_up() && bot_link.is_up() && bot.is_up();
+    let readiness = Readiness {
+        status: if ready { Status::Up } else { Status::Down },
+        components: Components { redis, bot_link, bot },
+    };
+    json_response(if ready { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE }, &readiness)
+}
+
+fn json_response<T: Serialize>(status: StatusCode, body: &T) -> Result<Response<Body>, RequestError> {
+    Ok(Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/json")
+        .body(serde_json::to_string(body).unwrap().into())?)
+}
+
+/// Keeps pinging the bot in the background until we shut down, so readiness checks don't have to wait for it
+pub fn watch_bot(ctx: Arc<ApiContext>, shutdown: ShutdownSignal) {
+    tokio::spawn(async move {
+        loop {
+            if let Err(e) = ctx.redis_link.ping_bot(PING_TIMEOUT).await {
+                log::debug!("GearBot did not answer a ping: {}", e);
+            }
+            tokio::select! {
+                _ = delay_for(Duration::from_secs(PING_INTERVAL)) => {},
+                _ = shutdown.clone().wait() => break
+            }
+        }
+    });
+}
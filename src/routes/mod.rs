@@ -1,6 +1,8 @@
 mod hello;
 pub use hello::hello_world;
 
+pub mod health;
+
 mod team;
 pub use team::team_info;
 
@@ -9,11 +11,31 @@ pub use ws::ws;
 
 pub mod discord;
 
-use crate::error::RequestError;
-use hyper::{Body, Response, StatusCode};
+#[cfg(test)]
+mod tests;
+
+use crate::models::Access;
+use crate::router::{authed, RateLimit, RouteMeta, Router};
+use discord::{login, auth, user_info, logout, logout_everywhere, sessions, delete_session, api_keys, create_api_key, delete_api_key, invite, invite_callback};
 
-pub fn not_found() -> Result<Response<Body>, RequestError> {
-    Ok(Response::builder()
-        .status(StatusCode::NOT_FOUND)
-        .body(Body::empty())?)
+/// All routes of the api, paths are relative to `/api`
+pub fn router() -> Router {
+    Router::new()
+        .get("/hello", RouteMeta::public(), |_, _, _| hello_world())
+        .get("/health/live", RouteMeta::public().rate_limit(RateLimit::Exempt), |_, _, _| health::live())
+        .get("/health/ready", RouteMeta::public().rate_limit(RateLimit::Exempt), |ctx, _, _| health::ready(ctx))
+        .get("/team_info", RouteMeta::public().rate_limit(RateLimit::Bot), |ctx, _, _| team_info(ctx))
+        .get("/ws", RouteMeta::public(), |ctx, request, _| ws(ctx, request))
+        .get("/discord/login", RouteMeta::public().rate_limit(RateLimit::Login), |ctx, request, _| login(ctx, request))
+        .get("/discord/auth", RouteMeta::public().rate_limit(RateLimit::Login), |ctx, request, _| auth(ctx, request))
+        .get("/discord/user", RouteMeta::user(Access::Read), authed(|ctx, _, _, user| user_info(ctx, user)))
+        .post("/discord/logout", RouteMeta::public(), |ctx, request, _| logout(ctx, request))
+        .post("/discord/logout/everywhere", RouteMeta::session(), authed(|ctx, request, _, user| logout_everywhere(ctx, request, user)))
+        .get("/discord/sessions", RouteMeta::session(), authed(|ctx, _, _, user| sessions(ctx, user)))
+        .delete("/discord/sessions/{id}", RouteMeta::session(), authed(|ctx, _, params, user| delete_session(ctx, params, user)))
+        .get("/discord/api_keys", RouteMeta::session(), authed(|ctx, _, _, user| api_keys(ctx, user)))
+        .post("/discord/api_keys", RouteMeta::session(), authed(|ctx, request, _, user| create_api_key(ctx, request, user)))
+        .delete("/discord/api_keys/{id}", RouteMeta::session(), authed(|ctx, _, params, user| delete_api_key(ctx, params, user)))
+        .get("/discord/invite", RouteMeta::session().rate_limit(RateLimit::Login), authed(|ctx, request, _, user| invite(ctx, request, user)))
+        .get("/discord/invite/callback", RouteMeta::public().rate_limit(RateLimit::Login), |ctx, request, _| invite_callback(ctx, request))
 }
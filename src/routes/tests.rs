@@ -0,0 +1,763 @@
+//! Runs routes against a fake bot and in-memory storage, so no redis or GearBot is needed
+
+use crate::config::ApiConfig;
+use crate::crypto::TokenCipher;
+use crate::error::{CommunicationError, WSMessageError};
+use crate::metrics::Metrics;
+use crate::middleware::Pipeline;
+use crate::models::{Access, ApiKeyRequest, SessionInfo, UserGuild};
+use crate::redis::cluster::{ClusterConfig, ClusterMap};
+use crate::redis::fake::{FakeBot, MemoryStorage};
+use crate::redis::redis_link::RedisLink;
+use crate::redis::transport::Replies;
+use crate::redis::{MinimalGuildInfo, ReplyData, Request, TeamInfo, TeamMember, TeamSocials, UserInfo};
+use crate::routes::ws::{cookie_token, guild_list, identify};
+use crate::shutdown::{ConnectionTracker, ShutdownSignal};
+use crate::util::RemoteAddr;
+use crate::{api_key, session, util, ApiContext};
+use hyper::header::{ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_REQUEST_METHOD, ALLOW, AUTHORIZATION, COOKIE, LOCATION, ORIGIN, RETRY_AFTER, SET_COOKIE};
+use hyper::{body, Body, Client, HeaderMap, Method, StatusCode};
+use hyper_tls::HttpsConnector;
+use serde_json::{json, Value};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{broadcast, watch};
+use twilight_model::guild::Permissions;
+use twilight_model::id::GuildId;
+
+const CONFIG: &str = r#"
+redis = "unused"
+port = 4000
+application_id = 1
+client_secret = "secret"
+redirect_uri = "http://gearbot.local/api/discord/auth"
+domain = "gearbot.local"
+secure = false
+token_encryption_key = "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA="
+
+[rate_limits]
+enabled = true
+bot = { requests = 2, window = 60 }
+default = { requests = 2, window = 60 }
+"#;
+
+const USER_ID: u64 = 42;
+const SESSION: &str = "session_token";
+
+struct TestApi {
+    ctx: Arc<ApiContext>,
+    bot: FakeBot,
+    pipeline: Pipeline,
+    // dropping it would count as shutting down
+    _stop: watch::Sender<bool>,
+}
+
+impl TestApi {
+    fn new<F>(script: F) -> Self
+        where F: Fn(&Request) -> Option<ReplyData> + Send + Sync + 'static {
+        Self::build(None, |replies| FakeBot::new(replies, script))
+    }
+
+    /// GearBot split into `clusters` clusters with a shard each
+    fn clustered<F>(clusters: u64, script: F) -> Self
+        where F: Fn(Option<usize>, &Request) -> Option<ReplyData> + Send + Sync + 'static {
+        let config = ClusterConfig { shards: clusters, clusters: (0..clusters).map(|shard| vec![shard]).collect() };
+        Self::build(Some(ClusterMap::new(&config).unwrap()), |replies| FakeBot::clustered(replies, script))
+    }
+
+    fn build(clusters: Option<ClusterMap>, bot: impl FnOnce(Replies) -> FakeBot) -> Self {
+        let config = toml::from_str::<ApiConfig>(CONFIG).unwrap();
+        let metrics = Arc::new(Metrics::new());
+        let replies = Replies::new(metrics.clone());
+        let bot = bot(replies.clone());
+        let redis_link = RedisLink::from_parts(Box::new(MemoryStorage::default()), Box::new(bot.clone()), replies, clusters, metrics.clone());
+        let token_cipher = TokenCipher::new(&config.token_encryption_key).unwrap();
+        let (revoked_sessions, _) = broadcast::channel(20);
+        let (stop, shutdown) = ShutdownSignal::new();
+        let ctx = Arc::new(ApiContext {
+            config,
+            redis_link,
+            client: Client::builder().build(HttpsConnector::new()),
+            token_cipher,
+            revoked_sessions,
+            shutdown,
+            connections: Arc::new(ConnectionTracker::default()),
+            metrics,
+        });
+        TestApi { ctx, bot, pipeline: crate::pipeline(), _stop: stop }
+    }
+
+    /// A logged in dashboard session for `USER_ID`
+    async fn login(&self) {
+        self.ctx.redis_link.set(&format!("dash_token:{}", SESSION), &USER_ID, None).await.unwrap();
+        let info = SessionInfo {
+            id: session::session_id(SESSION),
+            token: SESSION.to_string(),
+            created_at: 0,
+            last_used: util::now(),
+            user_agent: None,
+            ip: None,
+        };
+        self.ctx.redis_link.hash_set(&format!("user_sessions:{}", USER_ID), &info.id, &info).await.unwrap();
+    }
+
+    /// An api key for `USER_ID` with this access to every guild
+    async fn api_key(&self, access: Access) -> String {
+        let request = serde_json::from_value::<ApiKeyRequest>(json!({
+            "name": "test",
+            "scopes": [{"access": access}],
+            "expires_in": 3600
+        })).unwrap();
+        api_key::create_api_key(&self.ctx, USER_ID, request).await.unwrap().1
+    }
+
+    async fn get(&self, path: &str, session: Option<&str>) -> (StatusCode, Value) {
+        let (status, _, body) = self.send(Method::GET, path, session).await;
+        (status, body)
+    }
+
+    async fn send(&self, method: Method, path: &str, session: Option<&str>) -> (StatusCode, HeaderMap, Value) {
+        self.send_json(method, path, session, None).await
+    }
+
+    async fn send_json(&self, method: Method, path: &str, session: Option<&str>, body: Option<Value>) -> (StatusCode, HeaderMap, Value) {
+        let mut request = hyper::Request::builder().method(method).uri(path);
+        if let Some(session) = session {
+            request = request.header(AUTHORIZATION, format!("Bearer {}", session));
+        }
+        let body = body.map(|body| Body::from(body.to_string())).unwrap_or_else(Body::empty);
+        let response = self.pipeline.handle(self.ctx.clone(), request.body(body).unwrap()).await;
+        let status = response.status();
+        let headers = response.headers().clone();
+        let bytes = body::to_bytes(response.into_body()).await.unwrap();
+        (status, headers, serde_json::from_slice(&bytes).unwrap_or(Value::Null))
+    }
+}
+
+fn team_member(username: &str) -> TeamMember {
+    TeamMember {
+        username: username.to_string(),
+        discriminator: "0001".to_string(),
+        id: "1".to_string(),
+        avatar: "avatar".to_string(),
+        socials: TeamSocials { twitter: None, github: None, website: None },
+        team: "Developers".to_string(),
+    }
+}
+
+fn user_info(id: u64) -> UserInfo {
+    UserInfo {
+        id: id.to_string(),
+        name: "Someone".to_string(),
+        discriminator: "1234".to_string(),
+        avatar: None,
+        bot_user: false,
+        system_user: false,
+        public_flags: None,
+    }
+}
+
+fn user_guild(id: u64, name: &str) -> UserGuild {
+    UserGuild {
+        id: GuildId(id),
+        name: name.to_string(),
+        icon: None,
+        owner: false,
+        permissions: Permissions::empty(),
+        features: Vec::new(),
+    }
+}
+
+fn guild_ids(list: &Value) -> Vec<&str> {
+    list.as_array().unwrap().iter().map(|guild| guild["id"].as_str().unwrap()).collect()
+}
+
+#[tokio::test]
+async fn team_info_asks_the_bot() {
+    let api = TestApi::new(|request| match request {
+        Request::TeamInfo => Some(ReplyData::TeamInfo(TeamInfo { members: vec![team_member("AEnterprise")] })),
+        _ => None
+    });
+
+    let (status, body) = api.get("/api/team_info", None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body[0]["username"], "AEnterprise");
+    assert!(matches!(api.bot.received().as_slice(), [Request::TeamInfo]));
+}
+
+#[tokio::test]
+async fn team_info_fails_fast_without_bot() {
+    let api = TestApi::new(|_| None);
+    api.bot.disconnect();
+
+    let (status, body) = api.get("/api/team_info", None).await;
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(body["code"], "bot_unavailable");
+    assert!(api.bot.received().is_empty());
+}
+
+#[tokio::test]
+async fn team_info_with_wrong_reply() {
+    let api = TestApi::new(|_| Some(ReplyData::Pong));
+
+    let (status, body) = api.get("/api/team_info", None).await;
+    assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+    assert_eq!(body["code"], "internal_error");
+}
+
+#[tokio::test]
+async fn user_info_for_session() {
+    let api = TestApi::new(|request| match request {
+        Request::UserInfo(id) => Some(ReplyData::UserInfo(Some(user_info(*id)))),
+        _ => None
+    });
+    api.login().await;
+
+    let (status, body) = api.get("/api/discord/user", Some(SESSION)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["id"], USER_ID.to_string());
+    assert!(matches!(api.bot.received().as_slice(), [Request::UserInfo(USER_ID)]));
+}
+
+#[tokio::test]
+async fn user_info_unknown_to_bot() {
+    let api = TestApi::new(|_| Some(ReplyData::UserInfo(None)));
+    api.login().await;
+
+    let (status, body) = api.get("/api/discord/user", Some(SESSION)).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert_eq!(body["code"], "unauthorized");
+}
+
+#[tokio::test]
+async fn user_info_needs_login() {
+    let api = TestApi::new(|_| Some(ReplyData::UserInfo(Some(user_info(USER_ID)))));
+
+    let (status, _) = api.get("/api/discord/user", None).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, _) = api.get("/api/discord/user", Some("unknown_session")).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert!(api.bot.received().is_empty());
+}
+
+#[tokio::test]
+async fn guild_list_splits_mutual_guilds() {
+    let api = TestApi::new(|request| match request {
+        Request::MutualGuilds(_) => Some(ReplyData::MutualGuildList(vec![
+            MinimalGuildInfo { id: 1, name: "Shared".to_string(), icon: None, owned: true, permissions: 8 }
+        ])),
+        _ => None
+    });
+    let guilds = vec![user_guild(1, "Shared"), user_guild(2, "Without GearBot")];
+    api.ctx.redis_link.set(&format!("guilds:{}", USER_ID), &guilds, None).await.unwrap();
+
+    let list = serde_json::to_value(guild_list(&api.ctx, USER_ID).await.unwrap()).unwrap();
+    assert_eq!(list["type"], "GuildList");
+    assert_eq!(guild_ids(&list["gearbot_servers"]), vec!["1"]);
+    assert_eq!(list["gearbot_servers"][0]["permissions"], 8);
+    assert_eq!(guild_ids(&list["available_servers"]), vec!["2"]);
+    assert_eq!(list["partial"], false);
+    assert!(matches!(api.bot.received().as_slice(), [Request::MutualGuilds(USER_ID)]));
+}
+
+#[tokio::test]
+async fn guild_list_without_discord_token() {
+    let api = TestApi::new(|_| Some(ReplyData::MutualGuildList(Vec::new())));
+
+    let error = guild_list(&api.ctx, USER_ID).await.unwrap_err();
+    assert_eq!(error.code(), "no_discord_token");
+}
+
+#[tokio::test]
+async fn guild_list_asks_every_cluster() {
+    let api = TestApi::clustered(2, |cluster, request| match request {
+        Request::MutualGuilds(_) => {
+            let id = cluster.unwrap() as u64 + 1;
+            Some(ReplyData::MutualGuildList(vec![
+                MinimalGuildInfo { id, name: format!("Guild {}", id), icon: None, owned: false, permissions: 0 }
+            ]))
+        }
+        _ => None
+    });
+    let guilds = vec![user_guild(1, "Guild 1"), user_guild(2, "Guild 2"), user_guild(3, "Without GearBot")];
+    api.ctx.redis_link.set(&format!("guilds:{}", USER_ID), &guilds, None).await.unwrap();
+
+    let list = serde_json::to_value(guild_list(&api.ctx, USER_ID).await.unwrap()).unwrap();
+    assert_eq!(guild_ids(&list["gearbot_servers"]), vec!["1", "2"]);
+    assert_eq!(guild_ids(&list["available_servers"]), vec!["3"]);
+    assert_eq!(list["partial"], false);
+    assert_eq!(api.bot.clusters(), vec![Some(0), Some(1)]);
+}
+
+#[tokio::test]
+async fn guild_list_without_every_cluster_is_partial() {
+    // so we don't have to wait for the missing cluster
+    tokio::time::pause();
+    let api = TestApi::clustered(2, |cluster, request| match (cluster, request) {
+        (Some(0), Request::MutualGuilds(_)) => Some(ReplyData::MutualGuildList(vec![
+            MinimalGuildInfo { id: 1, name: "Guild 1".to_string(), icon: None, owned: false, permissions: 0 }
+        ])),
+        _ => None
+    });
+    // shard 1, so on the cluster that doesn't answer
+    let unknown = 1 << 22;
+    let guilds = vec![user_guild(1, "Guild 1"), user_guild(2, "Without GearBot"), user_guild(unknown, "Unknown")];
+    api.ctx.redis_link.set(&format!("guilds:{}", USER_ID), &guilds, None).await.unwrap();
+
+    let start = tokio::time::Instant::now();
+    let list = serde_json::to_value(guild_list(&api.ctx, USER_ID).await.unwrap()).unwrap();
+    assert_eq!(guild_ids(&list["gearbot_servers"]), vec!["1"]);
+    assert_eq!(guild_ids(&list["available_servers"]), vec!["2"]);
+    assert_eq!(list["partial"], true);
+    // not the full deadline, the silent cluster only gets a few seconds after the other one answered
+    assert!(start.elapsed() < Duration::from_secs(10));
+}
+
+#[tokio::test]
+async fn guild_list_without_any_cluster_times_out() {
+    tokio::time::pause();
+    let api = TestApi::clustered(2, |_, _| None);
+    api.ctx.redis_link.set(&format!("guilds:{}", USER_ID), &Vec::<UserGuild>::new(), None).await.unwrap();
+
+    let error = guild_list(&api.ctx, USER_ID).await.unwrap_err();
+    assert!(matches!(error, WSMessageError::Communication(CommunicationError::Timeout)));
+}
+
+#[tokio::test]
+async fn team_info_goes_to_one_cluster() {
+    let api = TestApi::clustered(3, |_, request| match request {
+        Request::TeamInfo => Some(ReplyData::TeamInfo(TeamInfo { members: Vec::new() })),
+        _ => None
+    });
+
+    for _ in 0..2 {
+        let (status, _) = api.get("/api/team_info", None).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+    // taking turns
+    assert_eq!(api.bot.clusters(), vec![Some(0), Some(1)]);
+}
+
+#[tokio::test]
+async fn ping_needs_every_cluster() {
+    tokio::time::pause();
+    let api = TestApi::clustered(2, |cluster, request| match (cluster, request) {
+        (Some(0), Request::Ping) => Some(ReplyData::Pong),
+        _ => None
+    });
+    assert!(api.ctx.redis_link.ping_bot(5).await.is_err());
+    assert_eq!(api.ctx.redis_link.last_pong(), None);
+    assert_eq!(api.bot.clusters(), vec![Some(0), Some(1)]);
+
+    let api = TestApi::clustered(2, |_, request| match request {
+        Request::Ping => Some(ReplyData::Pong),
+        _ => None
+    });
+    api.ctx.redis_link.ping_bot(5).await.unwrap();
+    assert!(api.ctx.redis_link.last_pong().is_some());
+}
+
+fn ws_upgrade(origin: &str) -> hyper::Request<Body> {
+    hyper::Request::get("/api/ws")
+        .header(COOKIE, format!("token={}", SESSION))
+        .header(ORIGIN, origin)
+        .body(Body::empty())
+        .unwrap()
+}
+
+#[tokio::test]
+async fn ws_accepts_the_cookie_from_our_own_origin() {
+    // the default config allows every origin with `*`, that shouldn't stop our own dashboard
+    let api = TestApi::new(|_| None);
+    assert_eq!(cookie_token(&api.ctx, &ws_upgrade("http://gearbot.local")), Some(SESSION.to_string()));
+}
+
+#[tokio::test]
+async fn ws_refuses_the_cookie_from_other_sites() {
+    let api = TestApi::new(|_| None);
+    assert_eq!(cookie_token(&api.ctx, &ws_upgrade("https://evil.example")), None);
+    // same domain, but not what we are served as
+    assert_eq!(cookie_token(&api.ctx, &ws_upgrade("https://gearbot.local.evil.example")), None);
+}
+
+#[tokio::test]
+async fn ws_identifies_with_api_keys() {
+    let api = TestApi::new(|request| match request {
+        Request::UserInfo(id) => Some(ReplyData::UserInfo(Some(user_info(*id)))),
+        _ => None
+    });
+    let key = api.api_key(Access::Read).await;
+    let upgrade = hyper::Request::get("/api/ws")
+        .header(AUTHORIZATION, format!("Bearer {}", key))
+        .body(Body::empty())
+        .unwrap();
+    assert_eq!(cookie_token(&api.ctx, &upgrade), Some(key.clone()));
+    assert_eq!(identify(&api.ctx, &key).await.unwrap().0, USER_ID);
+    assert!(matches!(identify(&api.ctx, "gb_unknown").await, Err(WSMessageError::BadAuthorization)));
+}
+
+#[tokio::test]
+async fn public_routes_ignore_credentials() {
+    let api = TestApi::new(|_| None);
+    for path in &["/api/hello", "/health/live"] {
+        let (status, _) = api.get(path, Some("gb_not_a_real_key")).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+}
+
+#[tokio::test]
+async fn head_gets_no_body_and_wrong_methods_get_allow() {
+    let api = TestApi::new(|_| None);
+    let request = hyper::Request::head("/api/hello").body(Body::empty()).unwrap();
+    let response = api.pipeline.handle(api.ctx.clone(), request).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(body::to_bytes(response.into_body()).await.unwrap().is_empty());
+
+    let (status, headers, _) = api.send(Method::POST, "/api/hello", None).await;
+    assert_eq!(status, StatusCode::METHOD_NOT_ALLOWED);
+    assert_eq!(headers[ALLOW], "GET, HEAD, OPTIONS");
+}
+
+#[tokio::test]
+async fn preflights_are_answered_before_the_route() {
+    let api = TestApi::new(|_| None);
+    let request = hyper::Request::options("/api/discord/api_keys")
+        .header(ORIGIN, "https://gearbot.rocks")
+        .header(ACCESS_CONTROL_REQUEST_METHOD, "POST")
+        .body(Body::empty())
+        .unwrap();
+    let response = api.pipeline.handle(api.ctx.clone(), request).await;
+    // not turned away for the missing session either
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    assert_eq!(response.headers()[ACCESS_CONTROL_ALLOW_ORIGIN], "*");
+    assert_eq!(response.headers()[ACCESS_CONTROL_ALLOW_METHODS], "GET, POST, HEAD, OPTIONS");
+}
+
+#[tokio::test]
+async fn rate_limits_per_address() {
+    let api = TestApi::new(|request| match request {
+        Request::TeamInfo => Some(ReplyData::TeamInfo(TeamInfo { members: Vec::new() })),
+        _ => None
+    });
+    let get = |path: &str, ip: [u8; 4]| {
+        let mut request = hyper::Request::get(path).body(Body::empty()).unwrap();
+        request.extensions_mut().insert(RemoteAddr(SocketAddr::from((ip, 1234))));
+        api.pipeline.handle(api.ctx.clone(), request)
+    };
+
+    for _ in 0..2 {
+        assert_eq!(get("/api/team_info", [10, 0, 0, 1]).await.status(), StatusCode::OK);
+    }
+    let limited = get("/api/team_info", [10, 0, 0, 1]).await;
+    assert_eq!(limited.status(), StatusCode::TOO_MANY_REQUESTS);
+    let retry_after = limited.headers()[RETRY_AFTER].to_str().unwrap().parse::<u64>().unwrap();
+    assert!((1..=60).contains(&retry_after));
+    // others have their own count
+    assert_eq!(get("/api/team_info", [10, 0, 0, 2]).await.status(), StatusCode::OK);
+    // health checks are never limited
+    for _ in 0..5 {
+        assert_eq!(get("/health/live", [10, 0, 0, 1]).await.status(), StatusCode::OK);
+    }
+}
+
+#[tokio::test]
+async fn logout_everywhere_ends_sessions_and_clears_the_cookie() {
+    let api = TestApi::new(|_| None);
+    api.login().await;
+
+    let (status, headers, _) = api.send(Method::POST, "/api/discord/logout/everywhere", Some(SESSION)).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    assert!(headers[SET_COOKIE].to_str().unwrap().starts_with("token=; Max-Age=0;"));
+    let (status, _) = api.get("/api/discord/sessions", Some(SESSION)).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+}
+
+/// Starts a login like a browser would, returning the oauth2 state and the pre-login cookie
+async fn start_login(api: &TestApi) -> (String, String) {
+    let (status, headers, _) = api.send(Method::GET, "/api/discord/login", None).await;
+    assert_eq!(status, StatusCode::TEMPORARY_REDIRECT);
+    discord_redirect(&headers)
+}
+
+/// The state and pre-login cookie of a redirect to discord
+fn discord_redirect(headers: &HeaderMap) -> (String, String) {
+    let location = headers[LOCATION].to_str().unwrap().parse::<hyper::Uri>().unwrap();
+    let state = form_urlencoded::parse(location.query().unwrap().as_bytes())
+        .find(|(name, _)| name == "state")
+        .unwrap().1.into_owned();
+    let cookie = headers[SET_COOKIE].to_str().unwrap().split(';').next().unwrap().to_string();
+    (state, cookie)
+}
+
+/// Comes back from discord with the state, but without a code so a state that checks out never gets as far as talking to discord
+async fn finish_login(api: &TestApi, state: Option<&str>, cookie: &str) -> (StatusCode, Value) {
+    let path = match state {
+        Some(state) => format!("/api/discord/auth?state={}", state),
+        // without a query at all it's the code that's missing
+        None => "/api/discord/auth?code=from_discord".to_string()
+    };
+    let request = hyper::Request::get(path).header(COOKIE, cookie).body(Body::empty()).unwrap();
+    let response = api.pipeline.handle(api.ctx.clone(), request).await;
+    let status = response.status();
+    let bytes = body::to_bytes(response.into_body()).await.unwrap();
+    (status, serde_json::from_slice(&bytes).unwrap_or(Value::Null))
+}
+
+#[tokio::test]
+async fn auth_needs_a_state() {
+    let api = TestApi::new(|_| None);
+    let (_, cookie) = start_login(&api).await;
+    let (status, body) = finish_login(&api, None, &cookie).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(body["code"], "invalid_oauth_state");
+}
+
+#[tokio::test]
+async fn auth_refuses_expired_states() {
+    let api = TestApi::new(|_| None);
+    let (state, cookie) = start_login(&api).await;
+    // redis would have dropped it, the in memory storage needs a hand
+    api.ctx.redis_link.delete(&format!("oauth_state:{}", state)).await.unwrap();
+    let (status, body) = finish_login(&api, Some(&state), &cookie).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(body["code"], "invalid_oauth_state");
+}
+
+#[tokio::test]
+async fn auth_states_only_work_once() {
+    let api = TestApi::new(|_| None);
+    let (state, cookie) = start_login(&api).await;
+    // the state checks out, it's the missing code that stops this one
+    let (status, body) = finish_login(&api, Some(&state), &cookie).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(body["code"], "no_access_code");
+    let (status, body) = finish_login(&api, Some(&state), &cookie).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(body["code"], "invalid_oauth_state");
+}
+
+#[tokio::test]
+async fn auth_states_belong_to_the_browser_that_started_the_login() {
+    let api = TestApi::new(|_| None);
+    let (state, _) = start_login(&api).await;
+    let (_, other_browser) = start_login(&api).await;
+    let (status, body) = finish_login(&api, Some(&state), &other_browser).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(body["code"], "invalid_oauth_state");
+}
+
+/// Guilds of `USER_ID`, only the first two can have GearBot added by them
+async fn invite_guilds(api: &TestApi) {
+    let mut managed = user_guild(1, "Managed");
+    managed.permissions = Permissions::MANAGE_GUILD;
+    let mut owned = user_guild(2, "Owned");
+    owned.owner = true;
+    let guilds = vec![managed, owned, user_guild(3, "Just a member")];
+    api.ctx.redis_link.set(&format!("guilds:{}", USER_ID), &guilds, None).await.unwrap();
+}
+
+/// Comes back from discord after an invite with these query parameters
+async fn finish_invite(api: &TestApi, query: &str, cookie: &str) -> (StatusCode, HeaderMap, Value) {
+    let request = hyper::Request::get(format!("/api/discord/invite/callback?{}", query))
+        .header(COOKIE, cookie)
+        .body(Body::empty())
+        .unwrap();
+    let response = api.pipeline.handle(api.ctx.clone(), request).await;
+    let status = response.status();
+    let headers = response.headers().clone();
+    let bytes = body::to_bytes(response.into_body()).await.unwrap();
+    (status, headers, serde_json::from_slice(&bytes).unwrap_or(Value::Null))
+}
+
+#[tokio::test]
+async fn invites_need_permission_to_manage_the_guild() {
+    let api = TestApi::new(|_| None);
+    api.login().await;
+    invite_guilds(&api).await;
+
+    for guild_id in &[1, 2] {
+        let (status, headers, _) = api.send(Method::GET, &format!("/api/discord/invite?guild_id={}", guild_id), Some(SESSION)).await;
+        assert_eq!(status, StatusCode::TEMPORARY_REDIRECT);
+        assert!(headers[LOCATION].to_str().unwrap().contains(&format!("&guild_id={}&", guild_id)));
+    }
+    let (status, _, body) = api.send(Method::GET, "/api/discord/invite?guild_id=3", Some(SESSION)).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(body["code"], "forbidden");
+    let (status, _, _) = api.send(Method::GET, "/api/discord/invite?guild_id=4", Some(SESSION)).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let (status, _, body) = api.send(Method::GET, "/api/discord/invite?guild_id=abc", Some(SESSION)).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(body["code"], "invalid_guild_id");
+}
+
+#[tokio::test]
+async fn invite_callbacks_check_the_state() {
+    let api = TestApi::new(|_| None);
+    api.login().await;
+    invite_guilds(&api).await;
+    let (_, headers, _) = api.send(Method::GET, "/api/discord/invite?guild_id=1", Some(SESSION)).await;
+    let (state, _) = discord_redirect(&headers);
+    let (_, other_browser) = start_login(&api).await;
+
+    let (status, _, body) = finish_invite(&api, "code=from_discord&guild_id=1", &other_browser).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(body["code"], "invalid_oauth_state");
+    let (status, _, body) = finish_invite(&api, &format!("state={}&code=from_discord&guild_id=1", state), &other_browser).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(body["code"], "invalid_oauth_state");
+    // login states are no good either
+    let (login_state, cookie) = start_login(&api).await;
+    let (status, _, body) = finish_invite(&api, &format!("state={}&code=from_discord&guild_id=1", login_state), &cookie).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(body["code"], "invalid_oauth_state");
+}
+
+#[tokio::test]
+async fn invite_callbacks_refuse_other_guilds() {
+    let api = TestApi::new(|_| None);
+    api.login().await;
+    invite_guilds(&api).await;
+    let (_, headers, _) = api.send(Method::GET, "/api/discord/invite?guild_id=1", Some(SESSION)).await;
+    let (state, cookie) = discord_redirect(&headers);
+
+    // swapped for a guild they can't add GearBot to
+    let (status, _, body) = finish_invite(&api, &format!("state={}&code=from_discord&guild_id=3", state), &cookie).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(body["code"], "invalid_guild_id");
+    // and the state was burned trying
+    let (status, _, body) = finish_invite(&api, &format!("state={}&code=from_discord&guild_id=1", state), &cookie).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(body["code"], "invalid_oauth_state");
+}
+
+#[tokio::test]
+async fn cancelled_invites_go_back_to_the_dashboard() {
+    let api = TestApi::new(|_| None);
+    api.login().await;
+    invite_guilds(&api).await;
+    let (_, headers, _) = api.send(Method::GET, "/api/discord/invite?guild_id=1", Some(SESSION)).await;
+    let (state, cookie) = discord_redirect(&headers);
+
+    let (status, headers, _) = finish_invite(&api, &format!("state={}&error=access_denied", state), &cookie).await;
+    assert_eq!(status, StatusCode::TEMPORARY_REDIRECT);
+    assert_eq!(headers[LOCATION], "http://gearbot.local/dashboard");
+    assert!(headers[SET_COOKIE].to_str().unwrap().starts_with("login_session=; Max-Age=0;"));
+}
+
+/// Creates a key through the api, returning the key and it's description
+async fn create_key(api: &TestApi, request: Value) -> (StatusCode, Value) {
+    let (status, _, body) = api.send_json(Method::POST, "/api/discord/api_keys", Some(SESSION), Some(request)).await;
+    (status, body)
+}
+
+#[tokio::test]
+async fn api_keys_can_be_created_listed_and_revoked() {
+    let api = TestApi::new(|_| None);
+    api.login().await;
+
+    let (status, created) = create_key(&api, json!({"name": "bot", "scopes": [{"access": "read"}], "expires_in": 3600})).await;
+    assert_eq!(status, StatusCode::CREATED);
+    let key = created["key"].as_str().unwrap();
+    assert!(key.starts_with(api_key::KEY_PREFIX));
+
+    let (status, list) = api.get("/api/discord/api_keys", Some(SESSION)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(list.as_array().unwrap().len(), 1);
+    assert_eq!(list[0]["id"], created["id"]);
+    assert_eq!(list[0]["name"], "bot");
+    // the key itself is only shown once
+    assert!(list[0].get("key").is_none());
+
+    let path = format!("/api/discord/api_keys/{}", created["id"].as_str().unwrap());
+    let (status, _, _) = api.send(Method::DELETE, &path, Some(SESSION)).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    let (status, list) = api.get("/api/discord/api_keys", Some(SESSION)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(list.as_array().unwrap().is_empty());
+    let (status, _, _) = api.send(Method::DELETE, &path, Some(SESSION)).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn api_keys_are_validated() {
+    let api = TestApi::new(|_| None);
+    api.login().await;
+    let invalid = [
+        json!({"name": "", "scopes": [{"access": "read"}], "expires_in": 3600}),
+        json!({"name": "no scopes", "scopes": [], "expires_in": 3600}),
+        json!({"name": "forever", "scopes": [{"access": "read"}], "expires_in": 0}),
+        json!({"name": "too long", "scopes": [{"access": "read"}], "expires_in": 31536001}),
+        json!({"name": "one guild", "scopes": [{"access": "read", "guild": "1"}], "expires_in": 3600}),
+    ];
+    for request in invalid.iter() {
+        let (status, body) = create_key(&api, request.clone()).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{} was accepted", request);
+        assert_eq!(body["code"], "invalid_body");
+    }
+}
+
+#[tokio::test]
+async fn api_keys_are_only_stored_hashed() {
+    let api = TestApi::new(|_| None);
+    api.login().await;
+    let (_, created) = create_key(&api, json!({"name": "bot", "scopes": [{"access": "read"}], "expires_in": 3600})).await;
+    let key = created["key"].as_str().unwrap();
+
+    let stored = api.ctx.redis_link.hash_values::<Value>(&format!("user_api_keys:{}", USER_ID)).await.unwrap();
+    assert_eq!(stored.len(), 1);
+    let hash = stored[0]["hash"].as_str().unwrap();
+    assert_eq!(hash.len(), 64);
+    assert!(!stored[0].to_string().contains(key));
+    assert!(api.ctx.redis_link.get::<Value>(&format!("api_key:{}", key)).await.unwrap().is_none());
+    let by_hash = api.ctx.redis_link.get::<Value>(&format!("api_key:{}", hash)).await.unwrap().unwrap();
+    assert!(!by_hash.to_string().contains(key));
+}
+
+#[tokio::test]
+async fn api_keys_work_like_sessions_within_their_scope() {
+    let api = TestApi::new(|request| match request {
+        Request::UserInfo(id) => Some(ReplyData::UserInfo(Some(user_info(*id)))),
+        _ => None
+    });
+    let key = api.api_key(Access::Read).await;
+
+    let (status, body) = api.get("/api/discord/user", Some(&key)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["id"], USER_ID.to_string());
+    // managing keys and sessions needs a real session
+    let (status, body) = api.get("/api/discord/api_keys", Some(&key)).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(body["code"], "forbidden");
+    let (status, _) = api.get("/api/discord/user", Some("gb_made_up")).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn revoked_and_expired_api_keys_are_refused() {
+    let api = TestApi::new(|request| match request {
+        Request::UserInfo(id) => Some(ReplyData::UserInfo(Some(user_info(*id)))),
+        _ => None
+    });
+    api.login().await;
+
+    let revoked = api.api_key(Access::Read).await;
+    let id = api.ctx.redis_link.hash_values::<Value>(&format!("user_api_keys:{}", USER_ID)).await.unwrap()[0]["id"].as_str().unwrap().to_string();
+    let (status, _, _) = api.send(Method::DELETE, &format!("/api/discord/api_keys/{}", id), Some(SESSION)).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    let (status, _) = api.get("/api/discord/user", Some(&revoked)).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    // redis would have dropped it, the in memory storage needs a hand
+    let expired = api.api_key(Access::Read).await;
+    let index = format!("user_api_keys:{}", USER_ID);
+    let mut info = api.ctx.redis_link.hash_values::<Value>(&index).await.unwrap().remove(0);
+    info["expires_at"] = json!(util::now() - 1);
+    let hash = info["hash"].as_str().unwrap().to_string();
+    api.ctx.redis_link.set(&format!("api_key:{}", hash), &info, None).await.unwrap();
+    api.ctx.redis_link.hash_set(&index, info["id"].as_str().unwrap(), &info).await.unwrap();
+    let (status, _) = api.get("/api/discord/user", Some(&expired)).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (_, list) = api.get("/api/discord/api_keys", Some(SESSION)).await;
+    assert!(list.as_array().unwrap().is_empty());
+}
@@ -1,24 +1,20 @@
 use std::sync::Arc;
-use crate::ApiContext;
+use crate::{logging, ApiContext};
 use crate::error::WSMessageError;
-use tokio_tungstenite::tungstenite::Message;
-use serde::Serialize;
 use crate::routes::ws::models::{WSOutbound, UserGuildList, MinimalGuild};
 use crate::util::get_user_guilds;
 
 pub async fn guild_list(ctx: &Arc<ApiContext>, user_id: u64) -> Result<WSOutbound, WSMessageError> {
-    if let Some(token) = ctx.redis_link.get(&format!("access_token:{}", user_id)).await? {
-        // all guilds the user is in
-        let discord_list_handle = tokio::spawn(get_user_guilds(ctx.clone(), user_id, token));
-        //request mutual servers from the bot
-        let bot_list = ctx.redis_link.get_mutual_guilds(user_id).await?;
+    // all guilds the user is in
+    let discord_list_handle = logging::spawn(get_user_guilds(ctx.clone(), user_id));
+    //request mutual servers from the bot
+    let bot_list = ctx.redis_link.get_mutual_guilds(user_id).await?;
 
-        let discord_list = discord_list_handle.await.unwrap()?;
-
-        let bot_guilds = bot_list.iter().map(|guild| guild.id).collect::<Vec<u64>>();
+    if let Some(discord_list) = discord_list_handle.await.unwrap()? {
+        let bot_guilds = bot_list.data.iter().map(|guild| guild.id).collect::<Vec<u64>>();
 
         //TODO: filter gearbot permissions to see the guild?
-        let gearbot_servers = bot_list.iter().filter(|guild|true).map(|guild| MinimalGuild {
+        let gearbot_servers = bot_list.data.iter().filter(|_guild|true).map(|guild| MinimalGuild {
             id: guild.id.to_string(),
             name: guild.name.clone(),
             icon: guild.icon.clone(),
@@ -31,7 +27,9 @@ pub async fn guild_list(ctx: &Arc<ApiContext>, user_id: u64) -> Result<WSOutboun
 
 
         for guild in discord_list {
-            if !bot_guilds.contains(&guild.id.0) {
+            //GearBot might be in there, the cluster that would know didn't answer
+            let unknown = ctx.redis_link.cluster_for_guild(guild.id.0).is_some_and(|cluster| bot_list.missing.contains(&cluster));
+            if !bot_guilds.contains(&guild.id.0) && !unknown {
                 //TODO: check for manage server perm
                 available_servers.push(MinimalGuild {
                     id: guild.id.to_string(),
@@ -46,10 +44,10 @@ pub async fn guild_list(ctx: &Arc<ApiContext>, user_id: u64) -> Result<WSOutboun
 
         Ok(WSOutbound::GuildList(UserGuildList {
             gearbot_servers,
-            available_servers
+            available_servers,
+            partial: bot_list.is_partial()
         }))
     } else {
-        //TODO: try refresh token
         Err(WSMessageError::NoValidDiscordAuthToken)
     }
 
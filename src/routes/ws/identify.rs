@@ -1,15 +1,29 @@
-use crate::error::WSMessageError;
-use crate::ApiContext;
+use crate::error::{DatabaseError, WSMessageError};
+use crate::models::Access;
+use crate::{api_key, ApiContext};
 use crate::redis::UserInfo;
 use std::sync::Arc;
 
+/// Identifies with a session token or an api key
 pub async fn identify(ctx: &Arc<ApiContext>, token: &str) -> Result<(u64, UserInfo), WSMessageError> {
-    if let Some(user_id) = ctx.redis_link.get::<u64>(&format!("dash_token:{}", token)).await? {
+    if let Some(user_id) = token_user(ctx, token).await? {
         if let Some(user_info) = ctx.redis_link.get_user_info(user_id).await? {
             return Ok(
                 (user_id, user_info)
             );
         }
     }
-    return Err(WSMessageError::BadAuthorization);
-}
\ No newline at end of file
+    Err(WSMessageError::BadAuthorization)
+}
+
+/// The user a session token or api key belongs to, `None` once it's revoked or expired
+pub async fn token_user(ctx: &Arc<ApiContext>, token: &str) -> Result<Option<u64>, DatabaseError> {
+    if token.starts_with(api_key::KEY_PREFIX) {
+        //everything on the websocket only reads, and isn't about a single guild
+        Ok(api_key::get_api_key(ctx, token).await?
+            .filter(|key| key.allows(Access::Read, None))
+            .map(|key| key.user_id))
+    } else {
+        ctx.redis_link.get::<u64>(&format!("dash_token:{}", token)).await
+    }
+}
@@ -1,28 +1,32 @@
 use crate::error::{BadRequestError, RequestError, WSMessageError};
-use crate::ApiContext;
-use futures_util::{TryStreamExt, StreamExt, SinkExt};
-use hyper::header::{HeaderValue, CONNECTION, SEC_WEBSOCKET_ACCEPT, SEC_WEBSOCKET_KEY, UPGRADE};
+use crate::{api_key, logging, ApiContext, util};
+use crate::util::RequestId;
+use futures_util::{StreamExt, SinkExt};
+use hyper::header::{HeaderValue, CONNECTION, ORIGIN, SEC_WEBSOCKET_ACCEPT, SEC_WEBSOCKET_KEY, UPGRADE};
 use hyper::{Body, Request, Response, StatusCode};
 use sha1::{Digest, Sha1};
 use std::sync::Arc;
 use tokio_tungstenite::{tungstenite::protocol::Role::Server, WebSocketStream};
-use crate::util::get_user_id;
-use crate::routes::ws::models::{WSRequest, WSOutbound};
+use crate::routes::ws::models::{WSError, WSReply, WSRequest, WSRequestId, WSOutbound};
 use tokio_tungstenite::tungstenite::protocol::CloseFrame;
-use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
 use std::borrow::Cow;
-use futures_util::future::ready;
 use tokio_tungstenite::tungstenite::Message;
 use log::error;
-use std::sync::atomic::{AtomicBool, Ordering, AtomicU64};
+use tokio::sync::broadcast::RecvError;
+use tokio::time::{interval, Duration};
+
+/// How often (in seconds) identified sockets check if their session is still there
+///
+/// Revocations only reach sockets on the instance that did the revoking, this is how the others find out
+const SESSION_RECHECK: u64 = 30;
 
 mod models;
 mod identify;
 mod guild_list;
 
-use identify::identify;
-use guild_list::guild_list;
-use tokio::sync::Mutex;
+pub(super) use identify::identify;
+use identify::token_user;
+pub(super) use guild_list::guild_list;
 
 pub async fn ws(
     ctx: Arc<ApiContext>,
@@ -36,94 +40,86 @@ pub async fn ws(
         Some(key) => accept_key(key.as_bytes()),
         None => return Err(BadRequestError::MissingWsKey.into()),
     };
+    // the cookie is only sent along with the upgrade request, so we need to grab it now
+    let cookie_token = cookie_token(&ctx, &request);
+    let request_id = request.extensions().get::<RequestId>().map(|id| id.0.clone()).unwrap_or_default();
 
-    let mut authenticated = false;
-    if let Some(user_id) = get_user_id(&ctx, &request).await? {
-        authenticated = true;
-    }
-
-    tokio::spawn(async move {
+    // hyper forgets about upgraded connections, so we keep track of them for shutting down
+    let guard = ctx.connections.track();
+    tokio::spawn(logging::with_request_id(request_id, async move {
+        let _guard = guard;
         match request.into_body().on_upgrade().await {
             Ok(upgraded) => {
-                let mut ws = WebSocketStream::from_raw_socket(upgraded, Server, None).await;
-                let (mut s, receiver) = ws.split();
-                let authenticated = Arc::new(AtomicBool::new(false));
-                let user_id = Arc::new(AtomicU64::new(0));
-                let sender = Mutex::new(s);
-
-                let result = receiver.map_err(|e| {
-                    WSMessageError::Tungstenite(e)
-                })
-                    .try_for_each(|message| async {
-                        log::info!("{:?}", message);
-                        match {
-                            let request: Result<WSRequest, WSMessageError> = serde_json::from_slice(message.into_data().as_slice()).map_err(|e| WSMessageError::CorruptMessage(e));
-                            match request {
-                                Ok(request) => {
-                                    if !authenticated.load(Ordering::SeqCst) {
-                                        match request {
-                                            WSRequest::Identify { token } => {
-                                                let result = identify(&ctx, &token).await;
-                                                if result.is_ok() {
-                                                    let (id, info) = result.as_ref().unwrap();
-                                                    authenticated.store(true, Ordering::SeqCst);
-                                                    user_id.store(*id, Ordering::SeqCst);
-                                                    log::debug!("Authorization accepted for {}#{} ({})",info.name, info.discriminator, id);
-                                                }
-                                                Ok(WSOutbound::Welcome)
-                                            }
-                                            _ => Err(WSMessageError::NotAuthorized)
-                                        }
-                                    } else {
-                                        match request {
-                                            WSRequest::GuildList => {
-                                                guild_list(&ctx, user_id.load(Ordering::SeqCst)).await
-                                            }
-                                            WSRequest::Identify { .. } => {
-                                                Err(WSMessageError::AlreadyAuthorized)
-                                            }
-                                        }
-                                    }
-                                }
-                                Err(e) => {
-                                    Err(e)
-                                }
-                            }
-                        }
-                        {
-                            Ok(reply) => {
-                                sender.lock().await.send(Message::text(serde_json::to_string(&reply).unwrap())).await;
-                            }
-                            Err(e) => {
-                                error!("Websocket message error: {}", e);
-                                if e.closes_socket() {
-                                    return Err(e);
-                                }
-                            }
+                let ws = WebSocketStream::from_raw_socket(upgraded, Server, None).await;
+                let _counted = ctx.metrics.ws_connection();
+                let (mut sender, mut receiver) = ws.split();
+                let mut revoked = ctx.revoked_sessions.subscribe();
+                let mut recheck = interval(Duration::from_secs(SESSION_RECHECK));
+                // only known once they identified themselves
+                let mut session: Option<WSSession> = None;
 
+                // the loop only ends when something ended the session
+                let reason = loop {
+                    let message = tokio::select! {
+                        message = receiver.next() => message,
+                        revoked_token = revoked.recv() => {
+                            match (revoked_token, &session) {
+                                (Ok(token), Some(session)) if token == session.token => break WSMessageError::SessionRevoked,
+                                //we missed some, ours might have been one of them
+                                (Err(RecvError::Lagged(_)), Some(session)) if !still_valid(&ctx, session).await => break WSMessageError::SessionRevoked,
+                                _ => continue
+                            }
                         }
-
-                        Ok(())
-                    })
-                    .await;
-
-                let close_frame = match result {
-                    Ok(_) =>
-                        CloseFrame {
-                            code: CloseCode::Normal,
-                            reason: Cow::from("Session finished"),
+                        _ = recheck.tick() => match &session {
+                            Some(session) if !still_valid(&ctx, session).await => break WSMessageError::SessionRevoked,
+                            _ => continue
                         },
-                    Err(e) => CloseFrame {
-                        code: CloseCode::Error,
-                        reason: Cow::from(e.get_close_message()),
+                        _ = ctx.shutdown.clone().wait() => break WSMessageError::ServerRestarting
+                    };
+                    let message = match message {
+                        Some(Ok(Message::Close(_))) | None => break WSMessageError::ClosedGracefully,
+                        // tungstenite answers these for us
+                        Some(Ok(Message::Ping(_))) | Some(Ok(Message::Pong(_))) => continue,
+                        Some(Ok(message)) => message,
+                        Some(Err(e)) => break WSMessageError::Tungstenite(e)
+                    };
+                    let data = message.into_data();
+                    let message_id = serde_json::from_slice::<WSRequestId>(&data).ok()
+                        .and_then(|id| id.request_id)
+                        .filter(|id| logging::valid_request_id(id))
+                        .unwrap_or_else(logging::new_request_id);
+
+                    let handled = logging::with_request_id(message_id.clone(), async {
+                        log::info!("Websocket message: {}", String::from_utf8_lossy(&data));
+                        handle_message(&ctx, &mut session, &cookie_token, &data).await.map_err(|e| {
+                            error!("Websocket message error: {}", e);
+                            e
+                        })
+                    }).await;
+                    let (reply, closing_error) = match handled {
+                        Ok(reply) => (reply, None),
+                        Err(e) => (WSOutbound::Error(WSError::from(&e)), Some(e).filter(WSMessageError::closes_socket))
+                    };
+                    let reply = WSReply { request_id: &message_id, message: &reply };
+                    if let Err(e) = sender.send(Message::text(serde_json::to_string(&reply).unwrap())).await {
+                        break WSMessageError::Tungstenite(e);
+                    }
+                    if let Some(e) = closing_error {
+                        break e;
                     }
                 };
-                sender.lock().await.send(Message::Close(Some(close_frame))).await;
+
+                let close_frame = CloseFrame {
+                    code: reason.get_close_code(),
+                    reason: Cow::from(reason.get_message()),
+                };
+                // the client might already be gone, nothing left to do if so
+                let _ = sender.send(Message::Close(Some(close_frame))).await;
                 log::debug!("Websocket closed")
             }
             Err(e) => log::error!("Failed to upgrade a connection: {}", e),
         }
-    });
+    }));
 
     let mut upgrade_rsp = Response::builder()
         .status(StatusCode::SWITCHING_PROTOCOLS)
@@ -138,6 +134,65 @@ pub async fn ws(
     Ok(upgrade_rsp)
 }
 
+/// The dashboard session (or api key) a websocket identified with
+struct WSSession {
+    user_id: u64,
+    token: String,
+}
+
+/// If the session (or api key) wasn't revoked or expired, sockets stay open when redis fails as there is no telling
+async fn still_valid(ctx: &Arc<ApiContext>, session: &WSSession) -> bool {
+    match token_user(ctx, &session.token).await {
+        Ok(user) => user.is_some(),
+        Err(e) => {
+            log::warn!("Unable to check if a websocket session is still valid: {}", e);
+            true
+        }
+    }
+}
+
+async fn handle_message(ctx: &Arc<ApiContext>, session: &mut Option<WSSession>, cookie_token: &Option<String>, data: &[u8]) -> Result<WSOutbound, WSMessageError> {
+    let request: WSRequest = match serde_json::from_slice(data) {
+        Ok(request) => request,
+        Err(e) => {
+            ctx.metrics.ws_message("invalid");
+            return Err(WSMessageError::CorruptMessage(e));
+        }
+    };
+    ctx.metrics.ws_message(request.name());
+    match (session.as_ref(), request) {
+        (None, WSRequest::Identify { token }) => {
+            let token = token.or_else(|| cookie_token.clone()).ok_or(WSMessageError::BadAuthorization)?;
+            let (id, info) = identify(ctx, &token).await?;
+            *session = Some(WSSession { user_id: id, token });
+            log::debug!("Authorization accepted for {}#{} ({})", info.name, info.discriminator, id);
+            Ok(WSOutbound::Welcome)
+        }
+        (None, _) => Err(WSMessageError::NotAuthorized),
+        (Some(_), WSRequest::Identify { .. }) => Err(WSMessageError::AlreadyAuthorized),
+        (Some(session), WSRequest::GuildList) => guild_list(ctx, session.user_id).await,
+    }
+}
+
+/// The session token (or api key) the upgrade request came with, if the socket is allowed to identify with it
+///
+/// Browsers send cookies along no matter which site opened the socket and don't check CORS for websockets,
+/// so the cookie only counts when the page is on our own domain or one of the listed CORS origins
+pub(super) fn cookie_token(ctx: &Arc<ApiContext>, request: &Request<Body>) -> Option<String> {
+    let origin = request.headers().get(ORIGIN);
+    let own_origin = origin.and_then(|origin| origin.to_str().ok())
+        .is_some_and(|origin| origin.eq_ignore_ascii_case(&ctx.config.origin()));
+    //api keys aren't session tokens, they don't have a signature to check
+    if let Some(key) = util::get_bearer_token(request).filter(|token| token.starts_with(api_key::KEY_PREFIX)) {
+        return Some(key.to_string());
+    }
+    if util::get_bearer_token(request).is_some() || own_origin || ctx.config.cors.origin_listed(origin) {
+        util::get_session_token(ctx, request)
+    } else {
+        None
+    }
+}
+
 fn accept_key(key: &[u8]) -> HeaderValue {
     const WS_GUID: &[u8] = b"258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
 
@@ -1,26 +1,68 @@
-use uuid::Uuid;
+use crate::error::WSMessageError;
 use serde::{Serialize, Deserialize};
 
 #[derive(Debug, Deserialize)]
 #[serde(tag = "type")]
 pub enum WSRequest {
     Identify {
-        token: String
+        /// browsers can't read the session cookie, they leave this out so the session from the cookie is used instead
+        #[serde(default)]
+        token: Option<String>
     },
     GuildList,
 }
 
+impl WSRequest {
+    /// Name of the kind of message, for metrics
+    pub fn name(&self) -> &'static str {
+        match self {
+            WSRequest::Identify { .. } => "Identify",
+            WSRequest::GuildList => "GuildList",
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Clone)]
 #[serde(tag = "type")]
 pub enum WSOutbound {
     Welcome,
     GuildList(UserGuildList),
+    Error(WSError),
+}
+
+/// The id of an incoming message, clients can set it to match our replies to their requests
+#[derive(Debug, Deserialize)]
+pub struct WSRequestId {
+    #[serde(default)]
+    pub request_id: Option<String>,
+}
+
+/// Everything we send, with the id of the message it answers
+#[derive(Debug, Serialize)]
+pub struct WSReply<'a> {
+    pub request_id: &'a str,
+    #[serde(flatten)]
+    pub message: &'a WSOutbound,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct WSError {
+    pub code: &'static str,
+    pub message: &'static str,
+}
+
+impl From<&WSMessageError> for WSError {
+    fn from(e: &WSMessageError) -> Self {
+        WSError { code: e.code(), message: e.get_message() }
+    }
 }
 
 #[derive(Debug, Serialize, Clone)]
 pub struct UserGuildList {
     pub gearbot_servers: Vec<MinimalGuild>,
-    pub available_servers: Vec<MinimalGuild>
+    pub available_servers: Vec<MinimalGuild>,
+    /// some GearBot clusters didn't answer, the guilds they have are missing from both lists
+    pub partial: bool,
 }
 
 #[derive(Debug, Serialize, Clone)]
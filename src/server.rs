@@ -0,0 +1,142 @@
+use crate::ApiContext;
+use crate::config::BindAddress;
+use crate::error::StartupError;
+use crate::middleware::Pipeline;
+use crate::tls::{self, CertResolver};
+use crate::util::RemoteAddr;
+use hyper::server::accept;
+use hyper::server::conn::AddrStream;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use log::error;
+use std::convert::Infallible;
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::sync::Arc;
+use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
+use tokio_rustls::server::TlsStream;
+use tokio::task::JoinHandle;
+
+/// Everything a single connection needs to handle it's requests
+#[derive(Clone)]
+struct Connection {
+    context: Arc<ApiContext>,
+    pipeline: Arc<Pipeline>,
+    remote_addr: Option<RemoteAddr>,
+}
+
+impl Connection {
+    async fn handle(self, mut request: Request<Body>) -> Result<Response<Body>, Infallible> {
+        if let Some(remote_addr) = self.remote_addr {
+            request.extensions_mut().insert(remote_addr);
+        }
+        Ok(self.pipeline.handle(self.context, request).await)
+    }
+}
+
+fn is_metrics_request(context: &ApiContext, request: &Request<Body>) -> bool {
+    matches!(*request.method(), Method::GET | Method::HEAD) && request.uri().path() == context.config.metrics.path
+}
+
+/// Serves the metrics on their own address, away from the public api
+fn listen_metrics(context: Arc<ApiContext>, address: SocketAddr) -> Result<JoinHandle<()>, StartupError> {
+    let bind_error = |e: io::Error| StartupError::Bind(address.to_string(), e);
+    let listener = std::net::TcpListener::bind(address).map_err(bind_error)?;
+    let shutdown = context.shutdown.clone();
+    let server = Server::from_tcp(listener)
+        .map_err(|e| bind_error(io::Error::other(e)))?
+        .serve(make_service_fn(move |_: &AddrStream| {
+            let context = context.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request| {
+                    let response = if is_metrics_request(&context, &request) {
+                        context.metrics.render()
+                    } else {
+                        Response::builder().status(StatusCode::NOT_FOUND).body(Body::empty()).unwrap()
+                    };
+                    async move { Ok::<_, Infallible>(response) }
+                }))
+            }
+        }))
+        .with_graceful_shutdown(shutdown.wait());
+    Ok(spawn(&BindAddress::Tcp(address), server))
+}
+
+/// Starts listening on all addresses, everything is bound before any server starts so a bad address stops startup
+///
+/// The servers stop accepting connections once we start shutting down, and finish once their connections are done
+pub fn listen(context: Arc<ApiContext>, pipeline: Arc<Pipeline>, addresses: &[BindAddress]) -> Result<Vec<JoinHandle<()>>, StartupError> {
+    let acceptor = match &context.config.tls {
+        Some(config) => {
+            let resolver = CertResolver::new(config)?;
+            resolver.watch(config.reload_interval, context.shutdown.clone());
+            Some(tls::acceptor(resolver))
+        }
+        None => None
+    };
+
+    let mut servers = Vec::new();
+    if context.config.metrics.enabled {
+        servers.push(listen_metrics(context.clone(), context.config.metrics.bind)?);
+    }
+    for address in addresses {
+        let connection = Connection { context: context.clone(), pipeline: pipeline.clone(), remote_addr: None };
+        let bind_error = |e: io::Error| StartupError::Bind(address.to_string(), e);
+        match (address, &acceptor) {
+            // unix sockets are only reachable from the same machine, no point in encrypting those
+            (BindAddress::Tcp(addr), Some(acceptor)) => {
+                let listener = std::net::TcpListener::bind(addr).map_err(bind_error)?;
+                listener.set_nonblocking(true).map_err(bind_error)?;
+                let listener = TcpListener::from_std(listener).map_err(bind_error)?;
+                let incoming = tls::incoming(listener, acceptor.clone(), context.shutdown.clone());
+                let server = Server::builder(accept::from_stream(incoming))
+                    .serve(make_service_fn(move |conn: &TlsStream<TcpStream>| {
+                        let remote_addr = conn.get_ref().0.peer_addr().ok().map(RemoteAddr);
+                        let connection = Connection { remote_addr, ..connection.clone() };
+                        async move { Ok::<_, Infallible>(service_fn(move |request| connection.clone().handle(request))) }
+                    }))
+                    .with_graceful_shutdown(context.shutdown.clone().wait());
+                servers.push(spawn(address, server));
+            }
+            (BindAddress::Tcp(addr), None) => {
+                let listener = std::net::TcpListener::bind(addr).map_err(bind_error)?;
+                let server = Server::from_tcp(listener)
+                    .map_err(|e| bind_error(io::Error::other(e)))?
+                    .serve(make_service_fn(move |conn: &AddrStream| {
+                        let connection = Connection { remote_addr: Some(RemoteAddr(conn.remote_addr())), ..connection.clone() };
+                        async move { Ok::<_, Infallible>(service_fn(move |request| connection.clone().handle(request))) }
+                    }))
+                    .with_graceful_shutdown(context.shutdown.clone().wait());
+                servers.push(spawn(address, server));
+            }
+            (BindAddress::Unix(path), _) => {
+                // a socket left behind by a previous run would make binding fail
+                if fs::symlink_metadata(path).map(|meta| meta.file_type().is_socket()).unwrap_or(false) {
+                    fs::remove_file(path).map_err(bind_error)?;
+                }
+                let listener = UnixListener::bind(path).map_err(bind_error)?;
+                fs::set_permissions(path, fs::Permissions::from_mode(context.config.socket_mode)).map_err(bind_error)?;
+                let server = Server::builder(accept::from_stream(listener))
+                    .serve(make_service_fn(move |_: &UnixStream| {
+                        let connection = connection.clone();
+                        async move { Ok::<_, Infallible>(service_fn(move |request| connection.clone().handle(request))) }
+                    }))
+                    .with_graceful_shutdown(context.shutdown.clone().wait());
+                servers.push(spawn(address, server));
+            }
+        }
+    }
+    Ok(servers)
+}
+
+fn spawn<F>(address: &BindAddress, server: F) -> JoinHandle<()>
+    where F: std::future::Future<Output = Result<(), hyper::Error>> + Send + 'static {
+    let address = address.clone();
+    tokio::spawn(async move {
+        if let Err(e) = server.await {
+            error!("Server error on {}: {}", address, e);
+        }
+    })
+}
@@ -0,0 +1,130 @@
+use crate::ApiContext;
+use crate::error::DatabaseError;
+use crate::models::SessionInfo;
+use crate::util;
+use hyper::{Body, Request};
+use hyper::header::USER_AGENT;
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+
+/// How long a dashboard session lasts
+pub const SESSION_EXPIRY: u32 = 604800;
+
+/// How stale the last used timestamp of a session can get before we bother updating it
+const LAST_USED_ACCURACY: u64 = 60;
+
+/// Creates a new dashboard session for this user and returns the token for it
+pub async fn create_session(ctx: &Arc<ApiContext>, user_id: u64, request: &Request<Body>) -> Result<String, DatabaseError> {
+    let token = util::random_token(16);
+
+    ctx.redis_link.set(&format!("dash_token:{}", token), &user_id, Some(SESSION_EXPIRY)).await?;
+    register_session(ctx, user_id, &token, request).await?;
+
+    Ok(token)
+}
+
+/// Updates the last used time of the session, called every time it is used
+pub async fn touch_session(ctx: &Arc<ApiContext>, user_id: u64, token: &str, request: &Request<Body>) -> Result<(), DatabaseError> {
+    let key = format!("user_sessions:{}", user_id);
+    match ctx.redis_link.hash_get::<SessionInfo>(&key, &session_id(token)).await? {
+        Some(mut info) => {
+            let now = util::now();
+            if now.saturating_sub(info.last_used) >= LAST_USED_ACCURACY {
+                info.last_used = now;
+                ctx.redis_link.hash_set(&key, &info.id, &info).await?;
+            }
+            Ok(())
+        }
+        //sessions from before we kept track of them, better late then never
+        None => register_session(ctx, user_id, token, request).await
+    }
+}
+
+/// Lists all active sessions of this user
+pub async fn get_sessions(ctx: &Arc<ApiContext>, user_id: u64) -> Result<Vec<SessionInfo>, DatabaseError> {
+    let key = format!("user_sessions:{}", user_id);
+    let now = util::now();
+    let mut sessions = vec![];
+    for info in ctx.redis_link.hash_values::<SessionInfo>(&key).await? {
+        //the dash_token key expires on it's own, but entries in the hash don't
+        if info.created_at + u64::from(SESSION_EXPIRY) < now {
+            ctx.redis_link.hash_delete(&key, &info.id).await?;
+        } else {
+            sessions.push(info);
+        }
+    }
+    sessions.sort_by_key(|info| info.created_at);
+    Ok(sessions)
+}
+
+/// Ends a single session
+///
+/// Returns the user it belonged to, or `None` if it didn't exist (anymore)
+pub async fn revoke_session(ctx: &Arc<ApiContext>, token: &str) -> Result<Option<u64>, DatabaseError> {
+    let user_id = ctx.redis_link.get::<u64>(&format!("dash_token:{}", token)).await?;
+    if let Some(user_id) = user_id {
+        ctx.redis_link.delete(&format!("dash_token:{}", token)).await?;
+        ctx.redis_link.hash_delete(&format!("user_sessions:{}", user_id), &session_id(token)).await?;
+        notify_revoked(ctx, token);
+    }
+    Ok(user_id)
+}
+
+/// Ends a session of this user by it's id
+///
+/// Returns `false` if the user has no session with this id
+pub async fn revoke_session_by_id(ctx: &Arc<ApiContext>, user_id: u64, id: &str) -> Result<bool, DatabaseError> {
+    let key = format!("user_sessions:{}", user_id);
+    match ctx.redis_link.hash_get::<SessionInfo>(&key, id).await? {
+        Some(info) => {
+            ctx.redis_link.delete(&format!("dash_token:{}", info.token)).await?;
+            ctx.redis_link.hash_delete(&key, id).await?;
+            notify_revoked(ctx, &info.token);
+            Ok(true)
+        }
+        None => Ok(false)
+    }
+}
+
+/// Ends every session this user has
+pub async fn revoke_all_sessions(ctx: &Arc<ApiContext>, user_id: u64) -> Result<(), DatabaseError> {
+    let key = format!("user_sessions:{}", user_id);
+    for info in ctx.redis_link.hash_values::<SessionInfo>(&key).await? {
+        ctx.redis_link.delete(&format!("dash_token:{}", info.token)).await?;
+        notify_revoked(ctx, &info.token);
+    }
+    ctx.redis_link.delete(&key).await?;
+    Ok(())
+}
+
+/// The id we show the user for a session, derived from the token so it can't be used to get the token
+pub fn session_id(token: &str) -> String {
+    let hash = Sha256::digest(token.as_bytes());
+    base64::encode_config(&hash[..12], base64::URL_SAFE_NO_PAD)
+}
+
+async fn register_session(ctx: &Arc<ApiContext>, user_id: u64, token: &str, request: &Request<Body>) -> Result<(), DatabaseError> {
+    let now = util::now();
+    let info = SessionInfo {
+        id: session_id(token),
+        token: token.to_string(),
+        created_at: now,
+        last_used: now,
+        user_agent: request.headers().get(USER_AGENT)
+            .and_then(|agent| agent.to_str().ok())
+            .map(|agent| agent.to_string()),
+        ip: util::get_client_ip(ctx, request).map(|ip| ip.to_string()),
+    };
+
+    //keep track of all sessions per user so they can see and end them
+    let key = format!("user_sessions:{}", user_id);
+    ctx.redis_link.hash_set(&key, &info.id, &info).await?;
+    ctx.redis_link.expire(&key, SESSION_EXPIRY).await?;
+    Ok(())
+}
+
+/// Lets any open websockets on this session know it's over
+fn notify_revoked(ctx: &Arc<ApiContext>, token: &str) {
+    //no websockets open means nobody is listening, that's fine
+    let _ = ctx.revoked_sessions.send(token.to_string());
+}
@@ -0,0 +1,70 @@
+use log::info;
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{watch, Notify};
+
+/// Lets everything that is running know we are shutting down
+#[derive(Clone)]
+pub struct ShutdownSignal(watch::Receiver<bool>);
+
+impl ShutdownSignal {
+    /// The signal, and the sender to trigger it with
+    pub fn new() -> (watch::Sender<bool>, Self) {
+        let (sender, receiver) = watch::channel(false);
+        (sender, ShutdownSignal(receiver))
+    }
+
+    /// Resolves once we are shutting down, right away if we already are
+    pub async fn wait(mut self) {
+        while let Some(stopping) = self.0.recv().await {
+            if stopping {
+                return;
+            }
+        }
+    }
+}
+
+/// Waits until we get told to stop, by SIGTERM (deploys) or SIGINT (ctrl+c)
+pub async fn wait_for_signal() -> io::Result<()> {
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    tokio::select! {
+        _ = terminate.recv() => info!("Received SIGTERM"),
+        _ = interrupt.recv() => info!("Received SIGINT"),
+    }
+    Ok(())
+}
+
+/// Keeps track of connections hyper doesn't know about anymore (websockets), so we can wait for them to close before exiting
+#[derive(Default)]
+pub struct ConnectionTracker {
+    active: AtomicUsize,
+    notify: Notify,
+}
+
+impl ConnectionTracker {
+    /// Marks a connection as active until the guard is dropped
+    pub fn track(self: &Arc<Self>) -> ConnectionGuard {
+        self.active.fetch_add(1, Ordering::SeqCst);
+        ConnectionGuard(self.clone())
+    }
+
+    /// Resolves once there are no active connections left
+    pub async fn drained(&self) {
+        while self.active.load(Ordering::SeqCst) > 0 {
+            self.notify.notified().await;
+        }
+    }
+}
+
+pub struct ConnectionGuard(Arc<ConnectionTracker>);
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        if self.0.active.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.0.notify.notify();
+        }
+    }
+}
@@ -0,0 +1,160 @@
+use crate::error::StartupError;
+use crate::shutdown::ShutdownSignal;
+use log::{debug, error, info};
+use serde::Deserialize;
+use std::fs::{self, File};
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::SystemTime;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio::time::{delay_for, timeout, Duration};
+use tokio_rustls::rustls::internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys};
+use tokio_rustls::rustls::sign::{self, CertifiedKey};
+use tokio_rustls::rustls::{ClientHello, NoClientAuth, ResolvesServerCert, ServerConfig};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+
+/// How long a client gets to finish the handshake before we give up on it
+const HANDSHAKE_TIMEOUT: u64 = 10;
+
+#[derive(Deserialize, Debug)]
+pub struct TlsConfig {
+    /// pem file with the certificate chain
+    pub cert: PathBuf,
+    /// pem file with the private key, either pkcs8 or rsa
+    pub key: PathBuf,
+    /// how often (in seconds) the files are checked for changes
+    #[serde(default = "default_reload_interval")]
+    pub reload_interval: u64,
+}
+
+fn default_reload_interval() -> u64 {
+    60
+}
+
+/// Hands out the current certificate, and swaps it out when the files on disk change
+pub struct CertResolver {
+    cert: PathBuf,
+    key: PathBuf,
+    current: RwLock<CertifiedKey>,
+    /// modification times of the cert and key we last loaded
+    modified: Mutex<(Option<SystemTime>, Option<SystemTime>)>,
+}
+
+impl CertResolver {
+    pub fn new(config: &TlsConfig) -> Result<Arc<Self>, StartupError> {
+        let modified = (modified(&config.cert), modified(&config.key));
+        let current = load(&config.cert, &config.key).map_err(StartupError::Tls)?;
+        Ok(Arc::new(CertResolver {
+            cert: config.cert.clone(),
+            key: config.key.clone(),
+            current: RwLock::new(current),
+            modified: Mutex::new(modified),
+        }))
+    }
+
+    /// Reloads the certificate if the files changed since we last loaded them, a broken new certificate keeps the old one in use
+    fn reload(&self) {
+        let latest = (modified(&self.cert), modified(&self.key));
+        let mut last = self.modified.lock().unwrap();
+        if *last == latest {
+            return;
+        }
+        match load(&self.cert, &self.key) {
+            Ok(key) => {
+                *self.current.write().unwrap() = key;
+                *last = latest;
+                info!("Reloaded the TLS certificate");
+            }
+            // renewals might write the cert and key one at a time, so try again next time
+            Err(e) => error!("Failed to reload the TLS certificate: {}", e),
+        }
+    }
+
+    /// Keeps checking for new certificates until we shut down
+    pub fn watch(self: &Arc<Self>, interval: u64, shutdown: ShutdownSignal) {
+        let resolver = self.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    _ = delay_for(Duration::from_secs(interval)) => resolver.reload(),
+                    _ = shutdown.clone().wait() => break
+                }
+            }
+        });
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<CertifiedKey> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|meta| meta.modified()).ok()
+}
+
+fn load(cert: &Path, key: &Path) -> Result<CertifiedKey, String> {
+    let chain = certs(&mut open(cert)?).map_err(|_| format!("{} is not a valid certificate file", cert.display()))?;
+    if chain.is_empty() {
+        return Err(format!("{} does not contain any certificates", cert.display()));
+    }
+    let mut keys = pkcs8_private_keys(&mut open(key)?).map_err(|_| format!("{} is not a valid key file", key.display()))?;
+    if keys.is_empty() {
+        keys = rsa_private_keys(&mut open(key)?).map_err(|_| format!("{} is not a valid key file", key.display()))?;
+    }
+    let key = keys.first().ok_or_else(|| format!("{} does not contain a private key", key.display()))?;
+    let key = sign::any_supported_type(key).map_err(|_| "Unsupported private key type".to_string())?;
+    Ok(CertifiedKey::new(chain, Arc::new(key)))
+}
+
+fn open(path: &Path) -> Result<BufReader<File>, String> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|e| format!("Unable to open {}: {}", path.display(), e))
+}
+
+/// Acceptor offering HTTP/2 and HTTP/1.1 through ALPN
+pub fn acceptor(resolver: Arc<CertResolver>) -> TlsAcceptor {
+    let mut config = ServerConfig::new(NoClientAuth::new());
+    config.cert_resolver = resolver;
+    config.set_protocols(&[b"h2".to_vec(), b"http/1.1".to_vec()]);
+    TlsAcceptor::from(Arc::new(config))
+}
+
+/// Accepts connections and does the TLS handshakes on the side, so slow clients don't hold up the rest
+pub fn incoming(mut listener: TcpListener, acceptor: TlsAcceptor, shutdown: ShutdownSignal) -> mpsc::Receiver<io::Result<TlsStream<TcpStream>>> {
+    let (sender, receiver) = mpsc::channel(32);
+    tokio::spawn(async move {
+        loop {
+            let stream = tokio::select! {
+                accepted = listener.accept() => match accepted {
+                    Ok((stream, _)) => stream,
+                    Err(e) => {
+                        // usually running out of file descriptors, give it a moment
+                        error!("Failed to accept a connection: {}", e);
+                        delay_for(Duration::from_millis(100)).await;
+                        continue;
+                    }
+                },
+                _ = shutdown.clone().wait() => break
+            };
+            let _ = stream.set_nodelay(true);
+            let acceptor = acceptor.clone();
+            let mut sender = sender.clone();
+            tokio::spawn(async move {
+                match timeout(Duration::from_secs(HANDSHAKE_TIMEOUT), acceptor.accept(stream)).await {
+                    Ok(Ok(stream)) => {
+                        let _ = sender.send(Ok(stream)).await;
+                    }
+                    Ok(Err(e)) => debug!("TLS handshake failed: {}", e),
+                    Err(_) => debug!("TLS handshake timed out"),
+                }
+            });
+        }
+    });
+    receiver
+}
@@ -1,26 +1,36 @@
-use crate::ApiContext;
+use crate::{ApiContext, cookie, metrics};
 use std::sync::Arc;
-use crate::models::UserGuild;
-use crate::error::{RequestError, ServerError, DatabaseError};
-use hyper::{Response, Body, Request, Method};
-use tokio_tungstenite::tungstenite::http::{StatusCode, HeaderValue};
+use std::collections::HashMap;
+use crate::models::{UserGuild, TokenResponse, BoundState};
+use crate::error::{RequestError, ServerError, DatabaseError, BadRequestError};
+use hyper::{Body, Request, Method, Response};
+use tokio_tungstenite::tungstenite::http::StatusCode;
 use hyper::body;
-use toml::value::Index;
-use hyper::header::COOKIE;
+use hyper::header::{CONTENT_TYPE, AUTHORIZATION};
+use rand::Rng;
+use serde::de::DeserializeOwned;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{SystemTime, UNIX_EPOCH};
 
-pub async fn get_user_guilds(ctx: Arc<ApiContext>, user_id: u64, token: String) -> Result<Vec<UserGuild>, RequestError>{
+/// How long we hang on to refresh tokens, discord doesn't tell us when they expire
+const REFRESH_TOKEN_EXPIRY: u32 = 2592000;
+
+/// Fetches the guilds the user is in
+///
+/// Returns `None` if we don't have a working discord token for them anymore, and they need to log in again
+pub async fn get_user_guilds(ctx: Arc<ApiContext>, user_id: u64) -> Result<Option<Vec<UserGuild>>, RequestError>{
     let key = format!("guilds:{}", user_id);
     //do we already have their guild list cached?
-    if let Some(data) = ctx.redis_link.get::<Vec<UserGuild>>(&key).await? {
-        Ok(data)
+    let cached = ctx.redis_link.get::<Vec<UserGuild>>(&key).await?;
+    ctx.metrics.cache_lookup("guilds", cached.is_some());
+    if let Some(data) = cached {
+        Ok(Some(data))
     } else {
         //nope, let's ask wumpus about it
-        let request = Request::builder()
-            .method(Method::GET)
-            .uri("https://discord.com/api/v8/users/@me/guilds")
-            .header("Authorization", format!("Bearer {}", token))
-            .body(Body::empty())?;
-        let response = ctx.client.request(request).await?;
+        let response = match discord_get(&ctx, user_id, "https://discord.com/api/v8/users/@me/guilds").await? {
+            Some(response) => response,
+            None => return Ok(None)
+        };
 
         if response.status() != StatusCode::OK {
             log::error!("Fetching user guilds failed with code {}: {:?}", response.status(), response.body());
@@ -29,31 +39,279 @@ pub async fn get_user_guilds(ctx: Arc<ApiContext>, user_id: u64, token: String)
         let bytes = body::to_bytes(response.into_body()).await?;
         let info: Vec<UserGuild> = serde_json::from_slice(bytes.as_ref()).map_err(|e| RequestError::Server(ServerError::DiscordError(format!("Failed to get user guilds: {}", e))))?;
         ctx.redis_link.set(&key, &info, Some(180)).await?;
-        Ok(info)
+        Ok(Some(info))
+
+    }
+}
+
+/// Makes a GET request to the discord api on behalf of the user
+///
+/// If discord no longer accepts the access token we have, it gets refreshed and the request is retried once.
+/// Returns `None` if there is no working token for this user
+pub async fn discord_get(ctx: &Arc<ApiContext>, user_id: u64, uri: &str) -> Result<Option<Response<Body>>, RequestError> {
+    let mut token = match get_access_token(ctx, user_id).await? {
+        Some(token) => token,
+        None => return Ok(None)
+    };
+    let mut refreshed = false;
+    loop {
+        let request = Request::builder()
+            .method(Method::GET)
+            .uri(uri)
+            .header(AUTHORIZATION, format!("Bearer {}", token))
+            .body(Body::empty())?;
+        let response = discord_request(ctx, request).await?;
+
+        if response.status() != StatusCode::UNAUTHORIZED || refreshed {
+            return Ok(Some(response));
+        }
+
+        //discord revoked it before it expired, try to get a new one
+        ctx.redis_link.delete(&format!("access_token:{}", user_id)).await.map_err(DatabaseError::from)?;
+        token = match refresh_access_token(ctx, user_id).await? {
+            Some(token) => token,
+            None => return Ok(None)
+        };
+        refreshed = true;
+    }
+}
+
+/// Sends a request to the discord api, counting it in the metrics
+pub async fn discord_request(ctx: &Arc<ApiContext>, request: Request<Body>) -> Result<Response<Body>, RequestError> {
+    let endpoint = metrics::discord_endpoint(request.uri().path());
+    let result = ctx.client.request(request).await;
+    ctx.metrics.discord_request(&endpoint, result.as_ref().ok().map(Response::status));
+    Ok(result?)
+}
+
+/// Gets the discord access token for this user, refreshing it if it expired
+pub async fn get_access_token(ctx: &Arc<ApiContext>, user_id: u64) -> Result<Option<String>, RequestError> {
+    if let Some(token) = ctx.redis_link.get(&format!("access_token:{}", user_id)).await? {
+        Ok(Some(token))
+    } else {
+        refresh_access_token(ctx, user_id).await
+    }
+}
+
+/// Trades the stored refresh token for a new access token
+///
+/// Returns `None` if there is no (valid) refresh token for this user
+pub async fn refresh_access_token(ctx: &Arc<ApiContext>, user_id: u64) -> Result<Option<String>, RequestError> {
+    let key = format!("refresh_token:{}", user_id);
+    let encrypted = match ctx.redis_link.get::<String>(&key).await? {
+        Some(encrypted) => encrypted,
+        None => return Ok(None)
+    };
+    let refresh_token = match ctx.token_cipher.decrypt(&encrypted) {
+        Some(token) => token,
+        None => {
+            log::warn!("Unable to decrypt the refresh token for {}, was the encryption key changed?", user_id);
+            return Ok(None)
+        }
+    };
+
+    let mut params = HashMap::with_capacity(3);
+    params.insert("grant_type", "refresh_token");
+    params.insert("refresh_token", &refresh_token);
+
+    match exchange_token(ctx, params).await? {
+        Some(info) => {
+            store_tokens(ctx, user_id, &info).await?;
+            Ok(Some(info.access_token))
+        }
+        None => {
+            //someone else might have beaten us to it, in which case this refresh token was already swapped for a new one
+            if let Some(token) = ctx.redis_link.get(&format!("access_token:{}", user_id)).await? {
+                return Ok(Some(token))
+            }
+            //nope, this one is just no good anymore
+            if ctx.redis_link.get::<String>(&key).await? == Some(encrypted) {
+                ctx.redis_link.delete(&key).await.map_err(DatabaseError::from)?;
+            }
+            Ok(None)
+        }
+    }
+}
+
+/// Calls the discord oauth2 token endpoint with the given grant, our client credentials are added automatically
+///
+/// The redirect uri and scopes of the dashboard login are used, unless the grant specifies it's own.
+/// Returns `None` if discord rejected the grant
+pub async fn exchange_token(ctx: &Arc<ApiContext>, mut params: HashMap<&str, &str>) -> Result<Option<TokenResponse>, RequestError> {
+    let id = ctx.config.application_id.to_string();
+    params.insert("client_id", id.as_str());
+    params.insert("client_secret", &ctx.config.client_secret);
+    params.entry("redirect_uri").or_insert(&ctx.config.redirect_uri);
+    params.entry("scope").or_insert("identify guilds");
+    //assemble the request
+    let request = Request::builder()
+        .method(Method::POST)
+        .uri("https://discord.com/api/oauth2/token")
+        .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
+        .body(Body::from(serde_urlencoded::to_string(params).unwrap()))?;
 
+    //make the request
+    let response = discord_request(ctx, request).await?;
+    //make sure it went ok
+    if response.status().is_client_error() {
+        log::debug!("Discord rejected an oauth2 grant with code {}", response.status());
+        return Ok(None)
     }
+    if response.status() != StatusCode::OK {
+        log::error!("Discord token exchange failed with code {}: {:?}", response.status(), response.body());
+        return Err(RequestError::Server(ServerError::DiscordError("Oauth2 token exchange failed!".to_string())))
+    }
+
+    //get the entire body, no need for chunking since it's just the discord api
+    let bytes = body::to_bytes(response.into_body()).await?;
+    let info = serde_json::from_slice(bytes.as_ref()).map_err(|e| RequestError::Server(ServerError::DiscordError(format!("Failed to receive token response: {}", e))))?;
+    Ok(Some(info))
 }
 
-pub async fn get_user_id(ctx: &Arc<ApiContext>, request: &Request<Body>) -> Result<Option<u64>, DatabaseError>{
-    if let Some(cookies) = request.headers().get(COOKIE) {
-        //does this handle weird cookies with "; " in their name properly? nope
-        //do we care? nope: we don't set any cookies like that
-        if let Ok(value) = String::from_utf8(cookies.as_bytes().to_vec()) {
-            for cookie in value.split("; ") {
-                if let Some(index) = cookie.find("=") {
-                    //we can split it
-                    let (name, value) = cookie.split_at(index);
-                    if name == "token" {
-                        //get rid of the = at the start
-                        let token = &value[1..];
-                        //token acquired, validate it exists
-                        return Ok(ctx.redis_link.get::<u64>(&format!("dash_token:{}", token)).await?)
-                    }
+/// Revokes the discord authorization this user gave us and forgets about their tokens
+pub async fn revoke_discord_tokens(ctx: &Arc<ApiContext>, user_id: u64) -> Result<(), RequestError> {
+    let access_key = format!("access_token:{}", user_id);
+    let refresh_key = format!("refresh_token:{}", user_id);
+    //revoking either one of them revokes the entire grant, no need to refresh just to revoke
+    let token = match ctx.redis_link.get::<String>(&access_key).await? {
+        Some(token) => Some(token),
+        None => ctx.redis_link.get::<String>(&refresh_key).await?
+            .and_then(|encrypted| ctx.token_cipher.decrypt(&encrypted))
+    };
+
+    if let Some(token) = token {
+        let id = ctx.config.application_id.to_string();
+        let mut params = HashMap::with_capacity(3);
+        params.insert("client_id", id.as_str());
+        params.insert("client_secret", &ctx.config.client_secret);
+        params.insert("token", &token);
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri("https://discord.com/api/oauth2/token/revoke")
+            .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .body(Body::from(serde_urlencoded::to_string(params).unwrap()))?;
+        let response = discord_request(ctx, request).await?;
+        if response.status() != StatusCode::OK {
+            log::error!("Discord token revocation failed with code {}: {:?}", response.status(), response.body());
+            return Err(RequestError::Server(ServerError::DiscordError("Oauth2 token revocation failed!".to_string())))
+        }
+    }
+
+    ctx.redis_link.delete(&access_key).await.map_err(DatabaseError::from)?;
+    ctx.redis_link.delete(&refresh_key).await.map_err(DatabaseError::from)?;
+    //without a token we wouldn't be able to refresh these anyways
+    ctx.redis_link.delete(&format!("guilds:{}", user_id)).await.map_err(DatabaseError::from)?;
+    Ok(())
+}
 
-                }
+/// Stores the tokens we got from discord, the refresh token is encrypted before it goes into redis
+pub async fn store_tokens(ctx: &Arc<ApiContext>, user_id: u64, info: &TokenResponse) -> Result<(), DatabaseError> {
+    //if we already had an access token we overwrite it, usually gona be the same but expiry might be renewed
+    ctx.redis_link.set(&format!("access_token:{}", user_id), &info.access_token, Some(info.expires_in as u32)).await?;
+    let refresh_token = ctx.token_cipher.encrypt(&info.refresh_token);
+    ctx.redis_link.set(&format!("refresh_token:{}", user_id), &refresh_token, Some(REFRESH_TOKEN_EXPIRY)).await
+}
+
+/// Gets the session token this request was made with
+///
+/// Browsers send it along as a cookie, other tools can put it in the authorization header as a bearer token instead
+pub fn get_session_token(ctx: &Arc<ApiContext>, request: &Request<Body>) -> Option<String> {
+    match get_bearer_token(request) {
+        Some(value) => cookie::verify_session_value(ctx, value),
+        None => cookie::get_session_cookie(ctx, request)
+    }
+}
+
+/// Gets the bearer token from the authorization header, if there is one
+pub fn get_bearer_token(request: &Request<Body>) -> Option<&str> {
+    request.headers().get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.trim())
+        .and_then(|value| {
+            let (scheme, token) = value.split_at(value.find(' ')?);
+            //the scheme is case insensitive
+            if scheme.eq_ignore_ascii_case("bearer") {
+                Some(token.trim())
+            } else {
+                None
             }
+        })
+}
+
+/// Takes the state discord hands back to us out of storage, making sure it is one we gave out to this browser, and that it is only used once
+pub async fn take_state<T: DeserializeOwned + BoundState>(ctx: &Arc<ApiContext>, prefix: &str, request: &Request<Body>, state: Option<String>) -> Result<T, RequestError> {
+    let state = state.ok_or(BadRequestError::InvalidOAuthState)?;
+    let key = format!("{}:{}", prefix, state);
+    //expired states are gone from redis so they end up here as well
+    let stored = ctx.redis_link.get::<T>(&key).await?.ok_or(BadRequestError::InvalidOAuthState)?;
+    //burn it right away so it can't be used again, even if the rest of the flow fails
+    ctx.redis_link.delete(&key).await.map_err(DatabaseError::from)?;
+
+    match cookie::get_login_session(request) {
+        Some(session) if session == stored.session() => Ok(stored),
+        _ => Err(BadRequestError::InvalidOAuthState.into())
+    }
+}
+
+/// Address of whoever is on the other end of the connection, attached to every incoming request
+#[derive(Clone, Copy, Debug)]
+pub struct RemoteAddr(pub SocketAddr);
+
+/// Id to find everything related to a request back with, attached to every incoming request and included in error replies
+#[derive(Clone, Debug)]
+pub struct RequestId(pub String);
+
+/// Figures out the ip of the client that made this request
+///
+/// Only looks at `X-Forwarded-For` if we are configured to be behind a proxy, anyone can set that header otherwise
+pub fn get_client_ip(ctx: &Arc<ApiContext>, request: &Request<Body>) -> Option<IpAddr> {
+    if ctx.config.trust_forwarded_for {
+        let forwarded = request.headers().get("X-Forwarded-For")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| forwarded_client(value, ctx.config.proxy_hops));
+        if forwarded.is_some() {
+            return forwarded
         }
     }
-    Ok(None)
+    request.extensions().get::<RemoteAddr>().map(|addr| addr.0.ip())
+}
+
+/// The client in an `X-Forwarded-For` header that went through `hops` proxies
+///
+/// Every proxy appends the address it got the request from, so only the last `hops` entries can be trusted,
+/// anything before that came from the client and can be made up
+fn forwarded_client(header: &str, hops: usize) -> Option<IpAddr> {
+    if hops == 0 {
+        return None;
+    }
+    header.rsplit(',').nth(hops - 1).and_then(|ip| ip.trim().parse().ok())
+}
+
+/// Generates a random url and cookie safe string from the given amount of random bytes
+pub fn random_token(bytes: usize) -> String {
+    let mut token = vec![0u8; bytes];
+    rand::thread_rng().fill(token.as_mut_slice());
+    base64::encode_config(token, base64::URL_SAFE_NO_PAD)
+}
+
+/// Current unix timestamp in seconds
+pub fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-}
\ No newline at end of file
+    #[test]
+    fn forwarded_client_counts_from_the_right() {
+        let ip = |ip: &str| Some(ip.parse::<IpAddr>().unwrap());
+        assert_eq!(forwarded_client("1.1.1.1, 10.0.0.1", 1), ip("10.0.0.1"));
+        assert_eq!(forwarded_client("1.1.1.1, 10.0.0.1", 2), ip("1.1.1.1"));
+        assert_eq!(forwarded_client("2001:db8::1", 1), ip("2001:db8::1"));
+        // not enough entries, or something that isn't an ip
+        assert_eq!(forwarded_client("10.0.0.1", 2), None);
+        assert_eq!(forwarded_client("nonsense", 1), None);
+        assert_eq!(forwarded_client("10.0.0.1", 0), None);
+    }
+}